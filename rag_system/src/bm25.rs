@@ -0,0 +1,208 @@
+// Okapi BM25 keyword search over the chunks, which finds exact identifiers, part numbers and
+// rare names that embeddings blur together
+use rig::vector_store::{VectorStoreError, VectorStoreIndex};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::Document;
+
+// Term frequency saturation and document length normalization, the usual values
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+// Characters joining the parts of identifiers such as "XR-200", "v1.2.3" or "max_tokens"
+const JOINERS: &[char] = &['-', '_', '.', '/'];
+
+struct Entry {
+    document: Document,
+    term_counts: HashMap<String, usize>,
+    length: usize,
+}
+
+/// In-memory BM25 index over the content of the chunks
+pub struct Bm25Index {
+    entries: Vec<Entry>,
+    // Number of chunks containing each term
+    document_frequency: HashMap<String, usize>,
+    average_length: f64,
+}
+
+impl Bm25Index {
+    pub fn new(documents: impl IntoIterator<Item = Document>) -> Self {
+        let mut document_frequency: HashMap<String, usize> = HashMap::new();
+        let entries: Vec<Entry> = documents
+            .into_iter()
+            .map(|document| {
+                let terms = terms(&document.content);
+                let mut term_counts: HashMap<String, usize> = HashMap::new();
+                for term in &terms {
+                    *term_counts.entry(term.clone()).or_default() += 1;
+                }
+                for term in term_counts.keys() {
+                    *document_frequency.entry(term.clone()).or_default() += 1;
+                }
+                Entry {
+                    document,
+                    term_counts,
+                    length: terms.len(),
+                }
+            })
+            .collect();
+
+        let total_length: usize = entries.iter().map(|entry| entry.length).sum();
+        let average_length = total_length as f64 / entries.len().max(1) as f64;
+
+        Self {
+            entries,
+            document_frequency,
+            average_length,
+        }
+    }
+
+    /// The `n` best-scoring chunks for `query`, best first; chunks sharing no term with it are left out
+    pub fn search(&self, query: &str, n: usize) -> Vec<(f64, &Document)> {
+        let query_terms: HashSet<String> = terms(query).into_iter().collect();
+
+        let mut scored: Vec<(f64, &Entry)> = self
+            .entries
+            .iter()
+            .map(|entry| (self.score(entry, &query_terms), entry))
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        // Stable, so equal scores keep index order and results are reproducible
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        scored
+            .into_iter()
+            .take(n)
+            .map(|(score, entry)| (score, &entry.document))
+            .collect()
+    }
+
+    fn score(&self, entry: &Entry, query_terms: &HashSet<String>) -> f64 {
+        let length_ratio = entry.length as f64 / self.average_length.max(1.0);
+        query_terms
+            .iter()
+            .filter_map(|term| {
+                let count = *entry.term_counts.get(term)? as f64;
+                let saturation = count * (K1 + 1.0) / (count + K1 * (1.0 - B + B * length_ratio));
+                Some(self.idf(term) * saturation)
+            })
+            .sum()
+    }
+
+    // Rare terms weigh more; always positive, unlike the original formula
+    fn idf(&self, term: &str) -> f64 {
+        let total = self.entries.len() as f64;
+        let containing = self.document_frequency.get(term).copied().unwrap_or(0) as f64;
+        (1.0 + (total - containing + 0.5) / (containing + 0.5)).ln()
+    }
+}
+
+/// Lowercased words of `text`. Identifiers like "XR-200" are kept whole and also split into their
+/// parts, so "xr-200" matches exactly and "200" still matches part of it.
+pub fn terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric() && !JOINERS.contains(&c)) {
+        let word = word.trim_matches(JOINERS).to_lowercase();
+        if word.is_empty() {
+            continue;
+        }
+        if word.contains(JOINERS) {
+            terms.extend(
+                word.split(JOINERS)
+                    .filter(|part| !part.is_empty())
+                    .map(String::from),
+            );
+        }
+        terms.push(word);
+    }
+    terms
+}
+
+impl VectorStoreIndex for Bm25Index {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        self.search(query, n)
+            .into_iter()
+            .map(|(score, document)| {
+                let value = serde_json::to_value(document)?;
+                Ok((score, document.id.clone(), serde_json::from_value(value)?))
+            })
+            .collect()
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        Ok(self
+            .search(query, n)
+            .into_iter()
+            .map(|(score, document)| (score, document.id.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::document;
+
+    fn index() -> Bm25Index {
+        Bm25Index::new([
+            document(
+                "manual.txt#0",
+                "Replace the filter of the XR-200 pump every year.",
+            ),
+            document("manual.txt#1", "The XR-300 pump has no filter to replace."),
+            document(
+                "manual.txt#2",
+                "Pumps should be inspected by a qualified technician.",
+            ),
+        ])
+    }
+
+    #[test]
+    fn identifiers_are_kept_whole_and_split() {
+        assert_eq!(
+            terms("Set max_tokens, see v1.2."),
+            [
+                "set",
+                "max",
+                "tokens",
+                "max_tokens",
+                "see",
+                "v1",
+                "2",
+                "v1.2"
+            ]
+        );
+    }
+
+    #[test]
+    fn exact_identifier_ranks_first() {
+        let index = index();
+        let results = index.search("filter for the XR-200", 3);
+        assert_eq!(results[0].1.id, "manual.txt#0");
+    }
+
+    #[test]
+    fn rare_terms_outweigh_common_ones() {
+        // "pump" is in two chunks, "technician" in one
+        let index = index();
+        let results = index.search("pump technician", 3);
+        assert_eq!(results[0].1.id, "manual.txt#2");
+    }
+
+    #[test]
+    fn chunks_without_query_terms_are_left_out() {
+        let index = index();
+        assert_eq!(index.search("qualified", 3).len(), 1);
+        assert!(index.search("warranty", 3).is_empty());
+    }
+}
@@ -0,0 +1,571 @@
+// Interactive chat that shows the sources behind each answer, with slash commands for
+// inspecting and tuning retrieval
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use rig::completion::{CompletionModel, Message, ModelChoice};
+use rig::embeddings::EmbeddingModel;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::citations::{self, CitedAnswer, StreamingAnnotator};
+use crate::config::{CompletionConfig, Config};
+use crate::memory;
+use crate::pipeline::{self, Rag};
+use crate::store::StoredIndex;
+use crate::streaming::StreamingCompletionModel;
+
+// Shown by `/help`
+const HELP: &str = "\
+Commands:
+  /sources       show the chunks the last answer was based on
+  /k [N]         show or change how many chunks are retrieved per question
+  /reset         forget the conversation so far
+  /model [NAME]  show or change the chat model
+  /docs          list the indexed files
+  /save [PATH]   save the conversation as Markdown
+  /help          show this list
+  exit           quit";
+
+type Connect<C> = dyn Fn(&CompletionConfig) -> Result<C>;
+
+/// An interactive chat: questions with their history, and slash commands to inspect and tune
+/// retrieval on the way
+pub struct Session<E, C: CompletionModel> {
+    config: Config,
+    embedding_model: E,
+    completion_model: C,
+    // Creates the chat model `/model` switches to; without it the model can't be changed
+    connect: Option<Box<Connect<C>>>,
+    // The index the agent searches, kept to rebuild the agent around another chat model and
+    // to list for `/docs`
+    stored: StoredIndex,
+    rag: Rag<C>,
+    // What the model is sent as the conversation so far
+    history: Vec<Message>,
+    // Questions and their numbered answers, for `/sources` and `/save`
+    transcript: Vec<(String, CitedAnswer)>,
+}
+
+impl<E, C> Session<E, C>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    /// Brings the index up to date and builds the agent, like `pipeline::build_agent`
+    pub async fn start(
+        config: Config,
+        embedding_model: E,
+        completion_model: C,
+        reindex: bool,
+    ) -> Result<Self> {
+        let stored = pipeline::build_index(embedding_model.clone(), &config, reindex).await?;
+        let rag = pipeline::agent(
+            stored.clone(),
+            embedding_model.clone(),
+            completion_model.clone(),
+            &config,
+        )?;
+
+        Ok(Self {
+            config,
+            embedding_model,
+            completion_model,
+            connect: None,
+            stored,
+            rag,
+            history: Vec::new(),
+            transcript: Vec::new(),
+        })
+    }
+
+    /// Lets `/model NAME` switch to the chat model `connect` creates from the changed config
+    pub fn with_connect(
+        mut self,
+        connect: impl Fn(&CompletionConfig) -> Result<C> + 'static,
+    ) -> Self {
+        self.connect = Some(Box::new(connect));
+        self
+    }
+
+    /// Reads questions and commands from stdin until `exit`, printing each answer with numbered
+    /// citations and a footer listing the chunks it was based on. Unless `completion.stream` is
+    /// off, answers are printed as the model writes them.
+    pub async fn run(mut self) -> Result<()> {
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+
+        println!("Welcome to the chatbot! Type 'exit' to quit or /help for commands.");
+
+        loop {
+            print!("> ");
+            stdout.flush()?;
+
+            let mut input = String::new();
+            if stdin.read_line(&mut input)? == 0 {
+                break; // End of input
+            }
+
+            // A failed command doesn't end the chat
+            match parse_input(&input) {
+                Input::Nothing => {}
+                Input::Exit => break,
+                Input::Command { name, argument } => match self.command(name, argument) {
+                    Ok(output) => println!("{output}"),
+                    Err(e) => println!("{e:#}"),
+                },
+                Input::Question(question) => self.answer(question).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn answer(&mut self, question: &str) -> Result<()> {
+        let rag = &self.rag;
+        println!("========================== Response ============================");
+        let (response, answer) = if self.config.completion.stream {
+            let result = ask_streaming(rag, question, self.history.clone(), print_piece).await?;
+            println!();
+            result
+        } else {
+            let (response, answer) = ask(rag, question, self.history.clone()).await?;
+            println!("{}", answer.text);
+            (response, answer)
+        };
+
+        memory::record_turn(&mut self.history, question, response);
+
+        println!(
+            "\n{}================================================================\n",
+            answer.footer()
+        );
+        self.transcript.push((question.to_string(), answer));
+        Ok(())
+    }
+
+    /// Runs the slash command `/name argument`, returning what to show for it
+    pub fn command(&mut self, name: &str, argument: &str) -> Result<String> {
+        Ok(match name {
+            "sources" => self.sources(),
+            "k" if argument.is_empty() => {
+                format!("Retrieving {} chunks per question", self.rag.top_k)
+            }
+            "k" => {
+                let mut config = self.config.clone();
+                config.set("retrieval.top_k", argument)?;
+                config.validate()?;
+                // Only the number of chunks asked of the index changes, so nothing is rebuilt
+                self.rag.top_k = config.retrieval.top_k;
+                self.config = config;
+                format!("Retrieving {} chunks per question", self.rag.top_k)
+            }
+            "reset" => {
+                self.history.clear();
+                self.transcript.clear();
+                "Conversation cleared".to_string()
+            }
+            "model" if argument.is_empty() => format!(
+                "Answering with {} ({})",
+                self.config.completion.model(),
+                self.config.completion.provider
+            ),
+            "model" => {
+                let Some(connect) = &self.connect else {
+                    anyhow::bail!("The chat model can't be changed in this session");
+                };
+                let mut config = self.config.clone();
+                config.set("completion.model", argument)?;
+                config.validate()?;
+                self.completion_model = connect(&config.completion)?;
+                self.rebuild(config)?;
+                format!("Answering with {}", self.config.completion.model())
+            }
+            "docs" => self.documents(),
+            "save" => self.save(argument)?,
+            "help" => HELP.to_string(),
+            _ => anyhow::bail!("Unknown command /{name}; type /help for the list"),
+        })
+    }
+
+    // Recreates the agent with `config` for `/model`, over the same index
+    fn rebuild(&mut self, config: Config) -> Result<()> {
+        self.rag = pipeline::agent(
+            self.stored.clone(),
+            self.embedding_model.clone(),
+            self.completion_model.clone(),
+            &config,
+        )?;
+        self.config = config;
+        Ok(())
+    }
+
+    // The chunks the last answer was based on, cited ones first with their numbers
+    fn sources(&self) -> String {
+        let Some((_, answer)) = self.transcript.last() else {
+            return "No answer yet".to_string();
+        };
+        if answer.citations.is_empty() && answer.uncited.is_empty() {
+            return "The last answer wasn't based on any chunk".to_string();
+        }
+        let mut text = String::new();
+        for citation in &answer.citations {
+            let document = &citation.document;
+            text.push_str(&format!(
+                "[{}] {}\n{}\n\n",
+                citation.number,
+                citations::describe(document),
+                document.content
+            ));
+        }
+        for document in &answer.uncited {
+            text.push_str(&format!(
+                "(not cited) {}\n{}\n\n",
+                citations::describe(document),
+                document.content
+            ));
+        }
+        text.trim_end().to_string()
+    }
+
+    // The files in the index the agent searches, which may not have been saved
+    fn documents(&self) -> String {
+        let mut text = String::new();
+        for source in &self.stored.sources {
+            text.push_str(&format!("{} ({} chunks)\n", source.id, source.chunks.len()));
+        }
+        text.push_str(&format!(
+            "{} files, {} chunks",
+            self.stored.sources.len(),
+            self.stored.entries.len()
+        ));
+        text
+    }
+
+    // Writes each question with its numbered answer and sources to `path`, or to a new
+    // chat-<time>.md in the current directory
+    fn save(&self, path: &str) -> Result<String> {
+        let path = if path.is_empty() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            PathBuf::from(format!("chat-{}.md", now.as_secs()))
+        } else {
+            PathBuf::from(path)
+        };
+
+        let mut markdown = String::new();
+        for (question, answer) in &self.transcript {
+            markdown.push_str(&format!(
+                "## {question}\n\n{}\n\n{}\n",
+                answer.text,
+                answer.footer()
+            ));
+        }
+        std::fs::write(&path, markdown).with_context(|| format!("Failed to write {:?}", path))?;
+        Ok(format!(
+            "Saved {} questions to {}",
+            self.transcript.len(),
+            path.display()
+        ))
+    }
+}
+
+/// What a line typed into the chat asks for
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input<'a> {
+    Nothing,
+    Exit,
+    // `/name argument`, with the argument trimmed
+    Command { name: &'a str, argument: &'a str },
+    Question(&'a str),
+}
+
+/// Reads one line of chat input
+pub fn parse_input(line: &str) -> Input<'_> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Input::Nothing;
+    }
+    if line == "exit" {
+        return Input::Exit;
+    }
+    match line.strip_prefix('/') {
+        Some(command) => {
+            let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+            Input::Command {
+                name,
+                argument: argument.trim(),
+            }
+        }
+        None => Input::Question(line),
+    }
+}
+
+/// Answers one question, returning the raw response and the response with numbered citations.
+/// The history is cut to the memory's token budget, and a follow-up is searched for as the
+/// standalone question the memory condenses it into.
+pub async fn ask<C: CompletionModel>(
+    rag: &Rag<C>,
+    question: &str,
+    history: Vec<Message>,
+) -> Result<(String, CitedAnswer)> {
+    let (history, query) = remember(rag, question, history).await?;
+    let (request, retrieved) = rag.request(question, &query, history).await?;
+    let response = match request.send().await?.choice {
+        ModelChoice::Message(text) => text,
+        ModelChoice::ToolCall(name, _) => {
+            anyhow::bail!("The model called tool {name:?}, but the agent has no tools")
+        }
+    };
+    let answer = citations::annotate(&response, &retrieved);
+
+    Ok((response, answer))
+}
+
+/// Answers one question like `ask`, passing the numbered answer to `on_text` piece by piece
+/// as the model writes it
+pub async fn ask_streaming<C: StreamingCompletionModel>(
+    rag: &Rag<C>,
+    question: &str,
+    history: Vec<Message>,
+    mut on_text: impl FnMut(&str) + Send,
+) -> Result<(String, CitedAnswer)> {
+    let (history, query) = remember(rag, question, history).await?;
+    let (request, retrieved) = rag.request(question, &query, history).await?;
+    let mut annotator = StreamingAnnotator::new(retrieved);
+
+    let mut pieces = rag.model.stream(request.build()).await?;
+    while let Some(piece) = pieces.next().await {
+        let text = annotator.push(&piece?);
+        if !text.is_empty() {
+            on_text(&text);
+        }
+    }
+
+    let response = annotator.response().to_string();
+    let (rest, answer) = annotator.finish();
+    if !rest.is_empty() {
+        on_text(&rest);
+    }
+
+    Ok((response, answer))
+}
+
+// The part of `history` the model gets to see, and what to search for to answer `question`:
+// the standalone question condensed from that
+async fn remember<C: CompletionModel>(
+    rag: &Rag<C>,
+    question: &str,
+    history: Vec<Message>,
+) -> Result<(Vec<Message>, String)> {
+    let history = rag.memory.trim(history);
+    let query = rag.memory.search_query(question, &history).await?;
+    Ok((history, query))
+}
+
+/// Prints a piece of a streamed answer right away
+pub fn print_piece(text: &str) {
+    print!("{text}");
+    // A failed flush only delays the text
+    io::stdout().flush().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    use crate::providers::{hashed, mock};
+
+    type TestSession = Session<hashed::EmbeddingModel, mock::CompletionModel>;
+
+    // A chat over one baking and one sailing file, answering with `model`
+    async fn session(model: &mock::CompletionModel) -> (TempDir, TestSession) {
+        let dir = TempDir::new().unwrap();
+        let documents = dir.path().join("documents");
+        fs::create_dir(&documents).unwrap();
+        fs::write(
+            documents.join("baking.md"),
+            "Let sourdough rise for four to six hours.",
+        )
+        .unwrap();
+        fs::write(documents.join("sailing.txt"), "Reef the mainsail early.").unwrap();
+
+        let mut config = Config::default();
+        config
+            .set("documents.dir", documents.to_str().unwrap())
+            .unwrap();
+        config
+            .set("index.dir", dir.path().join("index").to_str().unwrap())
+            .unwrap();
+        config.set("embedding.provider", "hashed").unwrap();
+        config.set("memory.condense", "false").unwrap();
+        config.validate().unwrap();
+
+        let session = Session::start(
+            config,
+            hashed::EmbeddingModel::default(),
+            model.clone(),
+            false,
+        )
+        .await
+        .unwrap();
+        (dir, session)
+    }
+
+    fn error(result: Result<String>) -> String {
+        format!("{:#}", result.unwrap_err())
+    }
+
+    #[test]
+    fn lines_are_parsed_into_commands_and_questions() {
+        assert_eq!(parse_input("  \n"), Input::Nothing);
+        assert_eq!(parse_input("exit\n"), Input::Exit);
+        assert_eq!(
+            parse_input("/k  3 \n"),
+            Input::Command {
+                name: "k",
+                argument: "3"
+            }
+        );
+        assert_eq!(
+            parse_input("/sources"),
+            Input::Command {
+                name: "sources",
+                argument: ""
+            }
+        );
+        assert_eq!(parse_input(" How long? \n"), Input::Question("How long?"));
+    }
+
+    #[tokio::test]
+    async fn k_changes_top_k_in_place() {
+        let model = mock::CompletionModel::new(|_| "Answer".into());
+        let (_dir, mut session) = session(&model).await;
+
+        assert_eq!(
+            session.command("k", "1").unwrap(),
+            "Retrieving 1 chunks per question"
+        );
+        assert_eq!(session.rag.top_k, 1);
+        assert_eq!(session.config.retrieval.top_k, 1);
+
+        assert!(
+            error(session.command("k", "many")).starts_with("Invalid value for retrieval.top_k")
+        );
+        assert_eq!(
+            error(session.command("k", "0")),
+            "retrieval.top_k must be greater than 0"
+        );
+        assert_eq!(session.rag.top_k, 1);
+        assert_eq!(
+            session.command("k", "").unwrap(),
+            "Retrieving 1 chunks per question"
+        );
+
+        session
+            .answer("How long does sourdough rise?")
+            .await
+            .unwrap();
+        assert_eq!(
+            model.last_request().unwrap().document_ids(),
+            ["baking.md#0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn sources_and_reset_follow_the_conversation() {
+        let model = mock::CompletionModel::new(|_| "Four to six hours [baking.md#0].".into());
+        let (_dir, mut session) = session(&model).await;
+        session.command("k", "1").unwrap();
+        assert_eq!(session.command("sources", "").unwrap(), "No answer yet");
+
+        session
+            .answer("How long does sourdough rise?")
+            .await
+            .unwrap();
+        let sources = session.command("sources", "").unwrap();
+        assert!(sources.starts_with("[1] baking.md"), "{sources}");
+        assert!(sources.contains("four to six hours"));
+        assert_eq!(session.history.len(), 2);
+
+        assert_eq!(
+            session.command("reset", "").unwrap(),
+            "Conversation cleared"
+        );
+        assert!(session.history.is_empty());
+        assert_eq!(session.command("sources", "").unwrap(), "No answer yet");
+    }
+
+    #[tokio::test]
+    async fn save_writes_the_transcript_as_markdown() {
+        let model = mock::CompletionModel::new(|_| "Four to six hours [baking.md#0].".into());
+        let (dir, mut session) = session(&model).await;
+        session.command("k", "1").unwrap();
+        session
+            .answer("How long does sourdough rise?")
+            .await
+            .unwrap();
+
+        let path = dir.path().join("chat.md");
+        let output = session.command("save", path.to_str().unwrap()).unwrap();
+
+        assert_eq!(output, format!("Saved 1 questions to {}", path.display()));
+        let markdown = fs::read_to_string(&path).unwrap();
+        assert!(
+            markdown.starts_with("## How long does sourdough rise?\n\nFour to six hours [1].\n\nSources:\n  [1] baking.md"),
+            "{markdown}"
+        );
+    }
+
+    #[tokio::test]
+    async fn docs_lists_the_session_index() {
+        let model = mock::CompletionModel::new(|_| "Answer".into());
+        let (dir, mut session) = session(&model).await;
+        // Listed from memory, not from the saved file
+        fs::remove_dir_all(dir.path().join("index")).unwrap();
+
+        assert_eq!(
+            session.command("docs", "").unwrap(),
+            "baking.md (1 chunks)\nsailing.txt (1 chunks)\n2 files, 2 chunks"
+        );
+    }
+
+    #[tokio::test]
+    async fn model_switches_only_with_connect() {
+        let model = mock::CompletionModel::new(|_| "Old".into());
+        let (_dir, mut session) = session(&model).await;
+        assert_eq!(
+            error(session.command("model", "gpt-4o")),
+            "The chat model can't be changed in this session"
+        );
+
+        let switched = mock::CompletionModel::new(|_| "New".into());
+        let connected = switched.clone();
+        let mut session = session.with_connect(move |_| Ok(connected.clone()));
+        assert_eq!(
+            session.command("model", "gpt-4o").unwrap(),
+            "Answering with gpt-4o"
+        );
+        session
+            .answer("How long does sourdough rise?")
+            .await
+            .unwrap();
+        assert!(model.requests().is_empty());
+        assert_eq!(switched.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_an_error() {
+        let model = mock::CompletionModel::new(|_| "Answer".into());
+        let (_dir, mut session) = session(&model).await;
+
+        assert_eq!(
+            error(session.command("frobnicate", "")),
+            "Unknown command /frobnicate; type /help for the list"
+        );
+        assert_eq!(session.command("help", "").unwrap(), HELP);
+    }
+}
@@ -0,0 +1,603 @@
+// Splitting document text into overlapping chunks for embedding, with pluggable strategies
+use anyhow::Result;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::tokens;
+
+/// What chunk size and overlap are measured in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkUnit {
+    Chars,
+    Words,
+    // cl100k_base tokens, as counted by the embedding and chat models
+    Tokens,
+}
+
+impl FromStr for ChunkUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "chars" => Ok(ChunkUnit::Chars),
+            "words" => Ok(ChunkUnit::Words),
+            "tokens" => Ok(ChunkUnit::Tokens),
+            _ => anyhow::bail!(
+                "Unknown chunk unit {value:?}, expected \"chars\", \"words\" or \"tokens\""
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ChunkUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkUnit::Chars => write!(f, "chars"),
+            ChunkUnit::Words => write!(f, "words"),
+            ChunkUnit::Tokens => write!(f, "tokens"),
+        }
+    }
+}
+
+/// Where chunk boundaries may fall
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    // Any word boundary
+    Fixed,
+    // Sentence ends; sentences longer than a chunk fall back to word boundaries
+    Sentence,
+    // Blank lines between paragraphs, then sentences, then words
+    Paragraph,
+    // Paragraphs, lines, sentence ends and spaces, trying each separator in turn
+    Recursive,
+}
+
+impl FromStr for ChunkStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "fixed" => Ok(ChunkStrategy::Fixed),
+            "sentence" => Ok(ChunkStrategy::Sentence),
+            "paragraph" => Ok(ChunkStrategy::Paragraph),
+            "recursive" => Ok(ChunkStrategy::Recursive),
+            _ => anyhow::bail!(
+                "Unknown chunk strategy {value:?}, expected \"fixed\", \"sentence\", \"paragraph\" or \"recursive\""
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ChunkStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkStrategy::Fixed => write!(f, "fixed"),
+            ChunkStrategy::Sentence => write!(f, "sentence"),
+            ChunkStrategy::Paragraph => write!(f, "paragraph"),
+            ChunkStrategy::Recursive => write!(f, "recursive"),
+        }
+    }
+}
+
+/// Sliding window settings: each chunk is at most `size` units and repeats the last
+/// `overlap` units of the previous chunk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    pub size: usize,
+    pub overlap: usize,
+    pub unit: ChunkUnit,
+}
+
+/// Chunking settings for a run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkOptions {
+    pub window: Window,
+    pub strategy: ChunkStrategy,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            window: Window {
+                size: 2000, // Approximately 2000 characters per chunk
+                overlap: 200,
+                unit: ChunkUnit::Chars,
+            },
+            strategy: ChunkStrategy::Fixed,
+        }
+    }
+}
+
+impl ChunkOptions {
+    /// The chunker implementing the selected strategy
+    pub fn chunker(&self) -> Box<dyn Chunker> {
+        match self.strategy {
+            ChunkStrategy::Fixed => Box::new(FixedWindowChunker(self.window)),
+            ChunkStrategy::Sentence => Box::new(SentenceChunker(self.window)),
+            ChunkStrategy::Paragraph => Box::new(ParagraphChunker(self.window)),
+            ChunkStrategy::Recursive => Box::new(RecursiveChunker::new(self.window)),
+        }
+    }
+}
+
+// Recorded in the saved index, so changing any setting triggers re-chunking
+impl fmt::Display for ChunkOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} (overlap {}, {})",
+            self.window.size, self.window.unit, self.window.overlap, self.strategy
+        )
+    }
+}
+
+/// A piece of document text to be embedded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub text: String,
+    // Byte range of the chunk in the text it was cut from
+    pub span: Range<usize>,
+}
+
+/// A way of cutting document text into chunks
+pub trait Chunker: Send + Sync {
+    fn chunk(&self, text: &str) -> Vec<Chunk>;
+}
+
+/// Windows of whole words, ignoring any other structure
+pub struct FixedWindowChunker(pub Window);
+
+impl Chunker for FixedWindowChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        self.0.pack(self.0.segments(text, &[Split::Words]))
+    }
+}
+
+/// Windows of whole sentences
+pub struct SentenceChunker(pub Window);
+
+impl Chunker for SentenceChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        self.0
+            .pack(self.0.segments(text, &[Split::Sentences, Split::Words]))
+    }
+}
+
+/// Windows of whole paragraphs, keeping the blank line between them
+pub struct ParagraphChunker(pub Window);
+
+impl Chunker for ParagraphChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        self.0.pack(
+            self.0
+                .segments(text, &[Split::Paragraphs, Split::Sentences, Split::Words]),
+        )
+    }
+}
+
+/// Splits on the first separator, and splits again on the next one only the pieces that are
+/// still larger than a chunk
+pub struct RecursiveChunker {
+    pub window: Window,
+    pub separators: Vec<String>,
+}
+
+impl RecursiveChunker {
+    /// Uses paragraph breaks, line breaks, sentence ends and spaces, in that order
+    pub fn new(window: Window) -> Self {
+        Self {
+            window,
+            separators: ["\n\n", "\n", ". ", " "].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl Chunker for RecursiveChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let splits: Vec<Split> = self
+            .separators
+            .iter()
+            .map(|separator| Split::Separator(separator))
+            .chain([Split::Words])
+            .collect();
+        self.window.pack(self.window.segments(text, &splits))
+    }
+}
+
+// How a piece of text is divided into smaller segments
+enum Split<'a> {
+    Paragraphs,
+    Sentences,
+    Words,
+    Separator(&'a str),
+}
+
+impl Split<'_> {
+    // The pieces of `text`, and the text that joins them back together
+    fn apply<'t>(&self, text: &'t str) -> (Vec<&'t str>, String) {
+        match self {
+            Split::Paragraphs => (split_paragraphs(text), "\n\n".to_string()),
+            Split::Sentences => (split_sentences(text), " ".to_string()),
+            Split::Words => (text.split_whitespace().collect(), " ".to_string()),
+            // The separator stays with the piece before it, so a sentence keeps its period;
+            // only its trailing whitespace is left to join the pieces back together
+            Split::Separator(separator) => (
+                text.split_inclusive(separator)
+                    .filter(|piece| !piece.trim().is_empty())
+                    .collect(),
+                separator[separator.trim_end().len()..].to_string(),
+            ),
+        }
+    }
+}
+
+// A piece of text that never gets cut, and what goes between it and the previous segment
+struct Segment {
+    text: String,
+    joiner: String,
+    span: Range<usize>,
+}
+
+impl Window {
+    // Size of `text` in this window's unit
+    fn measure(&self, text: &str) -> usize {
+        match self.unit {
+            ChunkUnit::Chars => text.chars().count(),
+            ChunkUnit::Words => text.split_whitespace().count(),
+            // BPE merges the leading space into the word, so count the text as it appears mid-document
+            ChunkUnit::Tokens => tokens::count_tokens(&format!(" {text}")),
+        }
+    }
+
+    // Splits `text` with the first split, going on to the next one for pieces that don't fit a chunk
+    fn segments(&self, text: &str, splits: &[Split]) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        self.collect_segments(text, 0, splits, "", &mut segments);
+        segments
+    }
+
+    // `offset` is where `text` starts in the text originally passed to `segments`
+    fn collect_segments(
+        &self,
+        text: &str,
+        offset: usize,
+        splits: &[Split],
+        joiner: &str,
+        segments: &mut Vec<Segment>,
+    ) {
+        let Some((split, finer)) = splits.split_first() else {
+            return;
+        };
+
+        let (pieces, inner_joiner) = split.apply(text);
+        for (i, piece) in pieces.into_iter().enumerate() {
+            // The first piece is joined to whatever came before `text` itself
+            let joiner = if i == 0 {
+                joiner
+            } else {
+                inner_joiner.as_str()
+            };
+            let piece_offset = offset + offset_in(text, piece);
+
+            // Collapse line wraps and repeated spaces, except where a separator is meaningful whitespace
+            let normalized = match split {
+                Split::Separator(_) => piece.trim().to_string(),
+                _ => piece.split_whitespace().collect::<Vec<_>>().join(" "),
+            };
+
+            if !finer.is_empty() && self.measure(&normalized) > self.size {
+                self.collect_segments(piece, piece_offset, finer, joiner, segments);
+            } else if !normalized.is_empty() {
+                let trimmed = piece.trim();
+                let start = piece_offset + offset_in(piece, trimmed);
+                segments.push(Segment {
+                    text: normalized,
+                    joiner: joiner.to_string(),
+                    span: start..start + trimmed.len(),
+                });
+            }
+        }
+    }
+
+    // Packs consecutive segments into chunks of at most `size` units, each starting with
+    // up to `overlap` units from the end of the previous chunk
+    fn pack(&self, segments: Vec<Segment>) -> Vec<Chunk> {
+        let lengths: Vec<usize> = segments
+            .iter()
+            .map(|segment| self.measure(&segment.text))
+            .collect();
+        let joiner_length = |segment: &Segment| match self.unit {
+            ChunkUnit::Chars => segment.joiner.chars().count(),
+            ChunkUnit::Words | ChunkUnit::Tokens => 0,
+        };
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+
+        while start < segments.len() {
+            // Grow the window until the next segment would exceed the chunk size
+            // (a single oversized segment still becomes its own chunk)
+            let mut end = start;
+            let mut length = 0;
+            while end < segments.len() {
+                let added = lengths[end]
+                    + if end > start {
+                        joiner_length(&segments[end])
+                    } else {
+                        0
+                    };
+                if end > start && length + added > self.size {
+                    break;
+                }
+                length += added;
+                end += 1;
+            }
+
+            let mut text = segments[start].text.clone();
+            for segment in &segments[start + 1..end] {
+                text.push_str(&segment.joiner);
+                text.push_str(&segment.text);
+            }
+            chunks.push(Chunk {
+                text,
+                span: segments[start].span.start..segments[end - 1].span.end,
+            });
+
+            if end == segments.len() {
+                break;
+            }
+
+            // Step back from the end of this window to find where the next one starts,
+            // always moving at least one segment forward
+            let mut next = end;
+            let mut overlap = 0;
+            while next > start + 1 {
+                let added = lengths[next - 1]
+                    + if next < end {
+                        joiner_length(&segments[next])
+                    } else {
+                        0
+                    };
+                if overlap + added > self.overlap {
+                    break;
+                }
+                overlap += added;
+                next -= 1;
+            }
+            start = next;
+        }
+
+        chunks
+    }
+}
+
+// Byte offset of `inner` within `outer`, which it must be a slice of
+fn offset_in(outer: &str, inner: &str) -> usize {
+    inner.as_ptr() as usize - outer.as_ptr() as usize
+}
+
+// Groups of consecutive non-blank lines
+fn split_paragraphs(text: &str) -> Vec<&str> {
+    let mut paragraphs = Vec::new();
+    let mut start = None;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            if let Some(paragraph_start) = start.take() {
+                paragraphs.push(&text[paragraph_start..offset]);
+            }
+        } else if start.is_none() {
+            start = Some(offset);
+        }
+        offset += line.len();
+    }
+
+    if let Some(paragraph_start) = start {
+        paragraphs.push(&text[paragraph_start..]);
+    }
+
+    paragraphs
+}
+
+// Text up to and including `.`, `!` or `?` (plus closing quotes or brackets) followed by whitespace
+fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?') {
+            continue;
+        }
+
+        let mut end = i + c.len_utf8();
+        while let Some(&(j, next)) = chars.peek() {
+            if !matches!(next, '"' | '\'' | ')' | ']' | '\u{201d}' | '\u{2019}') {
+                break;
+            }
+            end = j + next.len_utf8();
+            chars.next();
+        }
+
+        let at_boundary = match chars.peek() {
+            Some(&(_, next)) => next.is_whitespace(),
+            None => true,
+        };
+        if at_boundary {
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+
+    sentences
+        .into_iter()
+        .filter(|sentence| !sentence.trim().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(size: usize, overlap: usize, unit: ChunkUnit) -> Window {
+        Window {
+            size,
+            overlap,
+            unit,
+        }
+    }
+
+    // Chunk texts, after checking that each span covers the same words as its chunk
+    fn chunk(chunker: &dyn Chunker, text: &str) -> Vec<String> {
+        let chunks = chunker.chunk(text);
+        for chunk in &chunks {
+            let covered = &text[chunk.span.clone()];
+            assert!(
+                covered.split_whitespace().eq(chunk.text.split_whitespace()),
+                "span {:?} covers {covered:?}, not {:?}",
+                chunk.span,
+                chunk.text
+            );
+        }
+        chunks.into_iter().map(|chunk| chunk.text).collect()
+    }
+
+    #[test]
+    fn windows_step_back_by_the_overlap() {
+        let chunker = FixedWindowChunker(window(3, 1, ChunkUnit::Words));
+        assert_eq!(
+            chunk(&chunker, "one two three four five six seven"),
+            ["one two three", "three four five", "five six seven"]
+        );
+
+        // Spaces between words count towards a size in chars
+        let chunker = FixedWindowChunker(window(7, 0, ChunkUnit::Chars));
+        assert_eq!(chunk(&chunker, "aaa  bbb\nccc"), ["aaa bbb", "ccc"]);
+    }
+
+    #[test]
+    fn windows_always_move_forward() {
+        // An overlap as large as the chunk would otherwise repeat the same window forever
+        let chunker = FixedWindowChunker(window(2, 5, ChunkUnit::Words));
+        assert_eq!(chunk(&chunker, "a b c d"), ["a b", "b c", "c d"]);
+    }
+
+    #[test]
+    fn oversized_segments_become_their_own_chunk() {
+        let chunker = FixedWindowChunker(window(5, 0, ChunkUnit::Chars));
+        assert_eq!(
+            chunk(&chunker, "tiny enormousword tiny"),
+            ["tiny", "enormousword", "tiny"]
+        );
+    }
+
+    #[test]
+    fn token_windows_count_tokens() {
+        let chunker = FixedWindowChunker(window(5, 0, ChunkUnit::Tokens));
+        assert_eq!(
+            chunk(&chunker, "one two three four five six seven eight"),
+            ["one two three four five", "six seven eight"]
+        );
+    }
+
+    #[test]
+    fn blank_text_has_no_chunks() {
+        for strategy in ["fixed", "sentence", "paragraph", "recursive"] {
+            let options = ChunkOptions {
+                strategy: strategy.parse().unwrap(),
+                ..ChunkOptions::default()
+            };
+            assert!(options.chunker().chunk(" \n\n \t").is_empty());
+        }
+    }
+
+    #[test]
+    fn sentences_are_kept_whole() {
+        let chunker = SentenceChunker(window(30, 0, ChunkUnit::Chars));
+        assert_eq!(
+            chunk(&chunker, "It rained. Then it stopped! Did it?"),
+            ["It rained. Then it stopped!", "Did it?"]
+        );
+
+        // Decimal points and closing quotes don't end a sentence early
+        let chunker = SentenceChunker(window(17, 0, ChunkUnit::Chars));
+        assert_eq!(
+            chunk(&chunker, "It weighs 3.5 kg. She said \"go.\" Done."),
+            ["It weighs 3.5 kg.", "She said \"go.\"", "Done."]
+        );
+    }
+
+    #[test]
+    fn spans_fall_on_character_boundaries() {
+        let text = "Grüße aus Köln. Schöne Grüße, schönes Wetter!";
+        let chunker = SentenceChunker(window(20, 0, ChunkUnit::Chars));
+        assert_eq!(
+            chunk(&chunker, text),
+            ["Grüße aus Köln.", "Schöne Grüße,", "schönes Wetter!"]
+        );
+
+        let chunks = chunker.chunk(text);
+        assert_eq!(&text[chunks[0].span.clone()], "Grüße aus Köln.");
+        assert_eq!(chunks[2].span.end, text.len());
+    }
+
+    #[test]
+    fn paragraphs_keep_their_break() {
+        let text = "First para\nwraps here.\n\n\nSecond para.";
+
+        let chunker = ParagraphChunker(window(100, 0, ChunkUnit::Chars));
+        assert_eq!(
+            chunk(&chunker, text),
+            ["First para wraps here.\n\nSecond para."]
+        );
+
+        let chunker = ParagraphChunker(window(25, 0, ChunkUnit::Chars));
+        let chunks = chunker.chunk(text);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].span, text.find("Second").unwrap()..text.len());
+    }
+
+    #[test]
+    fn recursive_splits_only_what_does_not_fit() {
+        let text = "Intro line\nSecond line\n\nNext block. More text here.";
+
+        let chunker = RecursiveChunker::new(window(100, 0, ChunkUnit::Chars));
+        assert_eq!(chunk(&chunker, text), [text]);
+
+        // The first paragraph fits and keeps its line break; the second is split at its sentence
+        let chunker = RecursiveChunker::new(window(24, 0, ChunkUnit::Chars));
+        assert_eq!(
+            chunk(&chunker, text),
+            ["Intro line\nSecond line", "Next block.", "More text here."]
+        );
+
+        // Every chunk is a piece of the original text
+        for chunk in chunker.chunk(text) {
+            assert_eq!(&text[chunk.span.clone()], chunk.text);
+        }
+    }
+
+    #[test]
+    fn options_parse_and_display() {
+        for unit in ["chars", "words", "tokens"] {
+            assert_eq!(unit.parse::<ChunkUnit>().unwrap().to_string(), unit);
+        }
+        for strategy in ["fixed", "sentence", "paragraph", "recursive"] {
+            assert_eq!(
+                strategy.parse::<ChunkStrategy>().unwrap().to_string(),
+                strategy
+            );
+        }
+        assert!("lines".parse::<ChunkStrategy>().is_err());
+        assert_eq!(
+            ChunkOptions::default().to_string(),
+            "2000 chars (overlap 200, fixed)"
+        );
+    }
+}
@@ -0,0 +1,269 @@
+// Turning the chunk ids the agent cites into numbered references with a sources list
+use std::fmt::Write;
+
+use crate::Document;
+
+// Appended to the preamble so the model marks which chunks support each statement
+pub const CITATION_INSTRUCTIONS: &str = "After each statement that uses a document, cite it by putting its id in square brackets, e.g. [notes/guide.pdf#3]. Cite several documents as [id1, id2]. Only cite ids of documents you were given.";
+
+/// A retrieved chunk referenced by an answer
+#[derive(Clone, Debug)]
+pub struct Citation {
+    pub number: usize,
+    pub document: Document,
+}
+
+/// An answer with its citation markers replaced by numbers
+#[derive(Clone, Debug)]
+pub struct CitedAnswer {
+    pub text: String,
+    pub citations: Vec<Citation>,
+    // Chunks the agent was given but didn't cite
+    pub uncited: Vec<Document>,
+}
+
+/// Replaces `[id]` markers naming retrieved chunks with `[1]`, `[2]`, ... in order of first use.
+/// Brackets that don't name retrieved chunks are left untouched.
+pub fn annotate(answer: &str, retrieved: &[Document]) -> CitedAnswer {
+    let mut text = String::with_capacity(answer.len());
+    let mut citations: Vec<Citation> = Vec::new();
+    let mut rest = answer;
+
+    while let Some(open) = rest.find('[') {
+        text.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+
+        let Some(close) = after.find(']') else {
+            rest = &rest[open..];
+            break;
+        };
+
+        // Every comma/semicolon separated part must be a retrieved id for the marker to be replaced
+        let ids: Vec<&str> = after[..close].split([',', ';']).map(str::trim).collect();
+        let documents: Option<Vec<&Document>> = ids
+            .iter()
+            .map(|id| retrieved.iter().find(|document| document.id == *id))
+            .collect();
+
+        match documents {
+            Some(documents) => {
+                let numbers: Vec<String> = documents
+                    .into_iter()
+                    .map(|document| number_for(&mut citations, document).to_string())
+                    .collect();
+                write!(text, "[{}]", numbers.join(", ")).unwrap();
+            }
+            None => text.push_str(&rest[open..open + 1 + close + 1]),
+        }
+
+        rest = &after[close + 1..];
+    }
+    text.push_str(rest);
+
+    let uncited = retrieved
+        .iter()
+        .filter(|document| {
+            !citations
+                .iter()
+                .any(|citation| citation.document.id == document.id)
+        })
+        .cloned()
+        .collect();
+
+    CitedAnswer {
+        text,
+        citations,
+        uncited,
+    }
+}
+
+// Text after an unclosed `[` is held back this long at most while waiting for the `]`; ids are
+// far shorter, so a longer bracket can't be a citation marker
+const MAX_MARKER_CHARS: usize = 200;
+
+/// Numbers citation markers like `annotate` while the answer streams in, holding back text
+/// that may be the start of a marker until its closing bracket arrives
+pub struct StreamingAnnotator {
+    retrieved: Vec<Document>,
+    response: String,
+    // Length of the annotated text handed out so far
+    emitted: usize,
+}
+
+impl StreamingAnnotator {
+    pub fn new(retrieved: Vec<Document>) -> Self {
+        Self {
+            retrieved,
+            response: String::new(),
+            emitted: 0,
+        }
+    }
+
+    /// Adds the next piece of the answer and returns the annotated text that can be shown now
+    pub fn push(&mut self, piece: &str) -> String {
+        self.response.push_str(piece);
+
+        let ready = match self.response.rfind('[') {
+            Some(open)
+                if !self.response[open..].contains(']')
+                    && self.response.len() - open <= MAX_MARKER_CHARS =>
+            {
+                open
+            }
+            _ => self.response.len(),
+        };
+        // Markers are numbered in order of first use, so annotating a longer prefix only
+        // appends to what was annotated before
+        let text = annotate(&self.response[..ready], &self.retrieved).text;
+        self.take(&text)
+    }
+
+    /// The answer as the model wrote it so far
+    pub fn response(&self) -> &str {
+        &self.response
+    }
+
+    /// The rest of the annotated text, and the whole answer with its citations
+    pub fn finish(mut self) -> (String, CitedAnswer) {
+        let answer = annotate(&self.response, &self.retrieved);
+        let rest = self.take(&answer.text);
+        (rest, answer)
+    }
+
+    fn take(&mut self, text: &str) -> String {
+        let new = text.get(self.emitted..).unwrap_or_default().to_string();
+        self.emitted = self.emitted.max(text.len());
+        new
+    }
+}
+
+// The number already assigned to `document`, or the next free one
+fn number_for(citations: &mut Vec<Citation>, document: &Document) -> usize {
+    if let Some(citation) = citations
+        .iter()
+        .find(|citation| citation.document.id == document.id)
+    {
+        return citation.number;
+    }
+
+    let number = citations.len() + 1;
+    citations.push(Citation {
+        number,
+        document: document.clone(),
+    });
+    number
+}
+
+/// Where in its file a chunk comes from: "p. 3", "pp. 3-5", or its section heading path
+pub fn location(document: &Document) -> Option<String> {
+    match (document.page_start, document.page_end, &document.section) {
+        (Some(start), Some(end), _) if start == end => Some(format!("p. {start}")),
+        (Some(start), Some(end), _) => Some(format!("pp. {start}-{end}")),
+        (_, _, Some(section)) => Some(format!("\"{section}\"")),
+        _ => None,
+    }
+}
+
+/// "file, location (id)"
+pub fn describe(document: &Document) -> String {
+    match location(document) {
+        Some(location) => format!("{}, {} ({})", document.source, location, document.id),
+        None => format!("{} ({})", document.source, document.id),
+    }
+}
+
+impl CitedAnswer {
+    /// Lists the cited chunks with their file and pages, then any retrieved chunks that weren't cited
+    pub fn footer(&self) -> String {
+        let mut footer = String::new();
+
+        if !self.citations.is_empty() {
+            footer.push_str("Sources:\n");
+            for citation in &self.citations {
+                writeln!(
+                    footer,
+                    "  [{}] {}",
+                    citation.number,
+                    describe(&citation.document)
+                )
+                .unwrap();
+            }
+        }
+
+        if !self.uncited.is_empty() {
+            footer.push_str("Also retrieved:\n");
+            for document in &self.uncited {
+                writeln!(footer, "  {}", describe(document)).unwrap();
+            }
+        }
+
+        if self.citations.is_empty() && self.uncited.is_empty() {
+            footer.push_str("No relevant documents found.\n");
+        }
+
+        footer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::document;
+
+    #[test]
+    fn streamed_markers_are_numbered_once_complete() {
+        let retrieved = vec![document("guide.md#0", ""), document("guide.md#1", "")];
+        let mut annotator = StreamingAnnotator::new(retrieved.clone());
+
+        let pieces = [
+            "Proof it ",
+            "overnight [guide",
+            ".md#1",
+            "] and bake [x] hot ",
+            "[guide.md#0].",
+        ];
+        let shown: Vec<String> = pieces.iter().map(|piece| annotator.push(piece)).collect();
+        let (rest, answer) = annotator.finish();
+
+        assert_eq!(
+            shown,
+            [
+                "Proof it ",
+                "overnight ",
+                "",
+                "[1] and bake [x] hot ",
+                "[2]."
+            ]
+        );
+        assert_eq!(rest, "");
+        assert_eq!(answer.text, annotate(&pieces.concat(), &retrieved).text);
+    }
+
+    #[test]
+    fn locations_name_pages_or_sections() {
+        let located = |pages: Option<(usize, usize)>, section: Option<&str>| {
+            let mut document = document("manual.pdf#0", "");
+            document.page_start = pages.map(|(start, _)| start);
+            document.page_end = pages.map(|(_, end)| end);
+            document.section = section.map(String::from);
+            location(&document)
+        };
+
+        assert_eq!(located(Some((3, 3)), None).as_deref(), Some("p. 3"));
+        assert_eq!(located(Some((3, 5)), None).as_deref(), Some("pp. 3-5"));
+        // Pages win over the section when a chunk has both
+        assert_eq!(
+            located(Some((4, 4)), Some("Setup")).as_deref(),
+            Some("p. 4")
+        );
+        assert_eq!(
+            located(None, Some("Setup > Installing")).as_deref(),
+            Some("\"Setup > Installing\"")
+        );
+        assert_eq!(located(None, None), None);
+        assert_eq!(
+            describe(&document("manual.pdf#0", "")),
+            "manual.pdf (manual.pdf#0)"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// Command-line arguments
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Ask questions about a directory of documents
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file [default: rag.toml if it exists, or RAG_CONFIG]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Directory scanned for documents (documents.dir)
+    #[arg(long, global = true)]
+    pub documents: Option<PathBuf>,
+
+    /// Directory the embedded index is saved in (index.dir)
+    #[arg(long, global = true)]
+    pub index: Option<PathBuf>,
+
+    /// Chat model answering the questions (completion.model)
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+
+    /// Override any config setting, e.g. --set chunking.size=500; may be repeated
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+
+    // Without a subcommand the interactive chat starts, as before subcommands existed
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// The config overrides given on the command line, as `(key, value)` pairs
+    pub fn config_overrides(&self) -> Vec<(String, String)> {
+        let mut overrides = self.overrides.clone();
+        if let Some(dir) = &self.documents {
+            overrides.push(("documents.dir".into(), dir.to_string_lossy().into()));
+        }
+        if let Some(dir) = &self.index {
+            overrides.push(("index.dir".into(), dir.to_string_lossy().into()));
+        }
+        if let Some(model) = &self.model {
+            overrides.push(("completion.model".into(), model.clone()));
+        }
+        overrides
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Build or update the index, then exit
+    Ingest(IndexArgs),
+    /// Answer one question and exit
+    Query {
+        /// The question to answer
+        question: String,
+        #[command(flatten)]
+        index: IndexArgs,
+    },
+    /// Answer questions interactively
+    Chat(IndexArgs),
+    /// Serve the HTTP API
+    Serve {
+        /// Address to listen on (server.addr) [default: 127.0.0.1:3000]
+        #[arg(long)]
+        addr: Option<SocketAddr>,
+        #[command(flatten)]
+        index: IndexArgs,
+    },
+    /// Show what the saved index contains
+    Stats,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct IndexArgs {
+    /// Ignore the saved index and embed every file again (same as RAG_REINDEX=1)
+    #[arg(long)]
+    pub reindex: bool,
+}
+
+// Splits at the first `=`, so the value may contain more of them
+fn parse_override(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {value:?}"))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!(
+            "expected a key before '=', got {:?}",
+            format!("={value}")
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn overrides_split_at_the_first_equals_sign() {
+        assert_eq!(
+            parse_override("chunking.size=500"),
+            Ok(pair("chunking.size", "500"))
+        );
+        assert_eq!(
+            parse_override(" completion.model =gpt-4o"),
+            Ok(pair("completion.model", "gpt-4o"))
+        );
+        assert_eq!(
+            parse_override("completion.url=http://localhost/v1?key=a=b"),
+            Ok(pair("completion.url", "http://localhost/v1?key=a=b"))
+        );
+        // An empty value is passed on; the setting decides whether it's allowed
+        assert_eq!(
+            parse_override("retrieval.min_score="),
+            Ok(pair("retrieval.min_score", ""))
+        );
+    }
+
+    #[test]
+    fn overrides_need_a_key_and_an_equals_sign() {
+        assert_eq!(
+            parse_override("chunking.size"),
+            Err("expected KEY=VALUE, got \"chunking.size\"".to_string())
+        );
+        assert_eq!(
+            parse_override(" =500"),
+            Err("expected a key before '=', got \"=500\"".to_string())
+        );
+    }
+
+    #[test]
+    fn set_is_parsed_from_the_command_line() {
+        let cli =
+            Cli::try_parse_from(["rag_system", "--set", "a.b=c=d", "--model", "gpt-4o"]).unwrap();
+        assert_eq!(
+            cli.config_overrides(),
+            [pair("a.b", "c=d"), pair("completion.model", "gpt-4o")]
+        );
+        assert!(Cli::try_parse_from(["rag_system", "--set", "a.b"]).is_err());
+    }
+}
@@ -0,0 +1,809 @@
+// Pipeline settings from `rag.toml`, environment variables and command-line flags
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::chunking::{ChunkOptions, ChunkStrategy, ChunkUnit, Window};
+use crate::grounding::NoContext;
+use crate::ingest::DiscoveryOptions;
+use crate::memory::DEFAULT_HISTORY_TOKENS;
+use crate::mmr;
+use crate::providers::{hashed, Endpoint, Provider};
+use crate::rerank::{RerankMode, DEFAULT_CANDIDATES};
+use crate::retrieval::RetrievalOptions;
+use crate::search::{SearchMode, DEFAULT_RRF_K};
+use crate::tokens::MAX_EMBEDDING_TOKENS;
+
+// Read from the current directory when neither `--config` nor `RAG_CONFIG` is given
+pub const DEFAULT_CONFIG_FILE: &str = "rag.toml";
+
+// Base instructions for the RAG agent
+pub const DEFAULT_PREAMBLE: &str = "You are a helpful assistant that answers questions based on the provided document context. When answering questions, try to synthesize information from multiple chunks if they're related.";
+
+// Environment variables and the keys they override
+const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("RAG_DOCUMENTS_DIR", "documents.dir"),
+    ("RAG_INCLUDE", "documents.include"),
+    ("RAG_EXCLUDE", "documents.exclude"),
+    ("RAG_INDEX_DIR", "index.dir"),
+    ("RAG_CHUNK_SIZE", "chunking.size"),
+    ("RAG_CHUNK_OVERLAP", "chunking.overlap"),
+    ("RAG_CHUNK_UNIT", "chunking.unit"),
+    ("RAG_CHUNK_STRATEGY", "chunking.strategy"),
+    ("RAG_EMBEDDING_PROVIDER", "embedding.provider"),
+    ("RAG_EMBEDDING_MODEL", "embedding.model"),
+    ("RAG_EMBEDDING_BASE_URL", "embedding.base_url"),
+    ("RAG_EMBEDDING_API_VERSION", "embedding.api_version"),
+    ("RAG_EMBEDDING_HEADERS", "embedding.headers"),
+    ("RAG_PROVIDER", "completion.provider"),
+    ("RAG_MODEL", "completion.model"),
+    ("RAG_BASE_URL", "completion.base_url"),
+    ("RAG_API_VERSION", "completion.api_version"),
+    ("RAG_HEADERS", "completion.headers"),
+    ("RAG_PREAMBLE", "completion.preamble"),
+    ("RAG_STREAM", "completion.stream"),
+    ("RAG_TOP_K", "retrieval.top_k"),
+    ("RAG_RETRIEVAL_MODE", "retrieval.mode"),
+    ("RAG_RERANK", "retrieval.rerank"),
+    ("RAG_MMR", "retrieval.mmr"),
+    ("RAG_MMR_LAMBDA", "retrieval.mmr_lambda"),
+    ("RAG_MIN_SCORE", "retrieval.min_score"),
+    ("RAG_NO_CONTEXT", "retrieval.no_context"),
+    ("RAG_CONTEXT_TOKENS", "retrieval.context_tokens"),
+    ("RAG_CONDENSE", "memory.condense"),
+    ("RAG_HISTORY_TOKENS", "memory.history_tokens"),
+    ("RAG_SERVER_ADDR", "server.addr"),
+];
+
+/// Every setting of the pipeline. Later sources override earlier ones:
+/// defaults, then `rag.toml`, then environment variables, then command-line flags.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub documents: DocumentsConfig,
+    pub index: IndexConfig,
+    pub chunking: ChunkingConfig,
+    pub embedding: EmbeddingConfig,
+    pub completion: CompletionConfig,
+    pub retrieval: RetrievalConfig,
+    pub memory: MemoryConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DocumentsConfig {
+    pub dir: PathBuf,
+    // Globs relative to `dir`; empty means every supported file
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Default for DocumentsConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("documents"),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexConfig {
+    pub dir: PathBuf,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("index"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkingConfig {
+    pub size: usize,
+    pub overlap: usize,
+    #[serde(deserialize_with = "from_str")]
+    pub unit: ChunkUnit,
+    #[serde(deserialize_with = "from_str")]
+    pub strategy: ChunkStrategy,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        let options = ChunkOptions::default();
+        Self {
+            size: options.window.size,
+            overlap: options.window.overlap,
+            unit: options.window.unit,
+            strategy: options.strategy,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub provider: Provider,
+    // Defaults to the provider's usual embedding model
+    pub model: Option<String>,
+    // Defaults to the provider's public API, or a local Ollama server
+    pub base_url: Option<String>,
+    pub api_version: Option<String>,
+    // Name of the environment variable holding the API key
+    pub api_key_env: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            provider: Provider::OpenAI,
+            model: None,
+            base_url: None,
+            api_version: None,
+            api_key_env: None,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl EmbeddingConfig {
+    pub fn model(&self) -> &str {
+        self.model
+            .as_deref()
+            .unwrap_or(self.provider.default_embedding_model())
+    }
+
+    /// The connection settings, with the API key read from the environment
+    pub fn endpoint(&self) -> Result<Endpoint> {
+        self.endpoint_with_env(&|name| std::env::var(name).ok())
+    }
+
+    /// Like `endpoint`, but reading the API key through `env` instead of the environment
+    pub fn endpoint_with_env(&self, env: &dyn Fn(&str) -> Option<String>) -> Result<Endpoint> {
+        endpoint(
+            "embedding",
+            self.provider,
+            &self.base_url,
+            &self.api_version,
+            &self.api_key_env,
+            &self.headers,
+            env,
+        )
+    }
+
+    /// Identifies the embedding model in the saved index. OpenAI models are recorded by name alone,
+    /// so indexes saved before providers were configurable stay valid.
+    pub fn fingerprint(&self) -> String {
+        match self.provider {
+            Provider::OpenAI => self.model().to_string(),
+            provider => format!("{provider}:{}", self.model()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompletionConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub provider: Provider,
+    // Defaults to the provider's usual chat model
+    pub model: Option<String>,
+    // Defaults to the provider's public API, or a local Ollama server
+    pub base_url: Option<String>,
+    pub api_version: Option<String>,
+    // Name of the environment variable holding the API key
+    pub api_key_env: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    // Citation instructions are always appended to it
+    pub preamble: String,
+    // Print answers in the CLI as the model writes them
+    pub stream: bool,
+}
+
+impl Default for CompletionConfig {
+    fn default() -> Self {
+        Self {
+            provider: Provider::OpenAI,
+            model: None,
+            base_url: None,
+            api_version: None,
+            api_key_env: None,
+            headers: BTreeMap::new(),
+            preamble: DEFAULT_PREAMBLE.to_string(),
+            stream: true,
+        }
+    }
+}
+
+impl CompletionConfig {
+    pub fn model(&self) -> &str {
+        self.model
+            .as_deref()
+            .unwrap_or(self.provider.default_completion_model())
+    }
+
+    /// The connection settings, with the API key read from the environment
+    pub fn endpoint(&self) -> Result<Endpoint> {
+        self.endpoint_with_env(&|name| std::env::var(name).ok())
+    }
+
+    /// Like `endpoint`, but reading the API key through `env` instead of the environment
+    pub fn endpoint_with_env(&self, env: &dyn Fn(&str) -> Option<String>) -> Result<Endpoint> {
+        endpoint(
+            "completion",
+            self.provider,
+            &self.base_url,
+            &self.api_version,
+            &self.api_key_env,
+            &self.headers,
+            env,
+        )
+    }
+}
+
+// Resolves the connection settings of the `section` config section, reading the API key from the
+// environment through `env`. Without an explicit `api_key_env`, a missing OPENAI_API_KEY is only an error for
+// OpenAI itself; gateways and local servers often need no key.
+fn endpoint(
+    section: &str,
+    provider: Provider,
+    base_url: &Option<String>,
+    api_version: &Option<String>,
+    api_key_env: &Option<String>,
+    headers: &BTreeMap<String, String>,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<Endpoint> {
+    let api_key = match api_key_env {
+        Some(name) => Some(
+            env(name)
+                .with_context(|| format!("{section}.api_key_env names {name}, which is not set"))?,
+        ),
+        None => match provider.default_api_key_env() {
+            Some(name) => match env(name).filter(|key| !key.is_empty()) {
+                Some(key) => Some(key),
+                None if base_url.is_none() => {
+                    anyhow::bail!(
+                        "{name} is not set (needed for {section}.provider = \"{provider}\")"
+                    )
+                }
+                None => None,
+            },
+            None => None,
+        },
+    };
+
+    let base_url = base_url
+        .clone()
+        .or_else(|| provider.default_base_url().map(String::from))
+        .with_context(|| format!("{section}.base_url must be set"))?;
+
+    Ok(Endpoint {
+        base_url,
+        api_key,
+        api_version: api_version.clone(),
+        headers: headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+    })
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrievalConfig {
+    // Number of chunks given to the agent with each question
+    pub top_k: usize,
+    // Maximum tokens across the retrieved chunks; unset means no limit
+    pub context_tokens: Option<usize>,
+    // Embedding similarity, BM25 keywords, or both fused by rank
+    #[serde(deserialize_with = "from_str")]
+    pub mode: SearchMode,
+    // Rank offset for reciprocal rank fusion in hybrid mode
+    pub rrf_k: f64,
+    // How the candidates are reordered before the best top_k are kept
+    #[serde(deserialize_with = "from_str")]
+    pub rerank: RerankMode,
+    // Chunks retrieved for the reranker or MMR to choose from
+    pub candidates: usize,
+    // Pick chunks by maximal marginal relevance, trading relevance for diversity
+    pub mmr: bool,
+    // 1 means relevance only, 0 diversity only
+    pub mmr_lambda: f64,
+    // Chunks less similar to the question are never given to the agent; unset keeps all.
+    // Vector mode only, where the score is the cosine similarity
+    pub min_score: Option<f64>,
+    // What to do when no chunk is left to answer from
+    #[serde(deserialize_with = "from_str")]
+    pub no_context: NoContext,
+}
+
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        Self {
+            top_k: 4,
+            context_tokens: None,
+            mode: SearchMode::Vector,
+            rrf_k: DEFAULT_RRF_K,
+            rerank: RerankMode::None,
+            candidates: DEFAULT_CANDIDATES,
+            mmr: false,
+            mmr_lambda: mmr::DEFAULT_LAMBDA,
+            min_score: None,
+            no_context: NoContext::Fallback,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    // Rewrite follow-up questions into standalone ones before searching, using the chat model
+    pub condense: bool,
+    // Maximum tokens of conversation history sent with each question; older turns are dropped
+    pub history_tokens: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            condense: true,
+            history_tokens: DEFAULT_HISTORY_TOKENS,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Address the HTTP API listens on
+    pub addr: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+        }
+    }
+}
+
+impl Config {
+    /// Loads `path` (or `RAG_CONFIG`, or `rag.toml` if it exists), then applies the environment
+    /// variable overrides and the `key=value` overrides given on the command line
+    pub fn load(path: Option<&Path>, overrides: &[(String, String)]) -> Result<Self> {
+        let path = path
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("RAG_CONFIG").map(PathBuf::from));
+
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+
+        let env: BTreeMap<String, String> = ENV_OVERRIDES
+            .iter()
+            .filter_map(|(name, _)| Some((name.to_string(), std::env::var(name).ok()?)))
+            .collect();
+        config.apply_overrides(&env, overrides)?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Applies the `RAG_*` variables found in `env`, then the `key=value` overrides, so the
+    /// command line wins over the environment
+    pub fn apply_overrides(
+        &mut self,
+        env: &BTreeMap<String, String>,
+        overrides: &[(String, String)],
+    ) -> Result<()> {
+        for (name, key) in ENV_OVERRIDES {
+            if let Some(value) = env.get(*name) {
+                self.set(key, value)
+                    .with_context(|| format!("Invalid environment variable {name}"))?;
+            }
+        }
+
+        for (key, value) in overrides {
+            self.set(key, value)
+                .with_context(|| format!("Invalid command-line override {key}={value}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Parses a config file; relative directories in it are relative to the file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        // Parse errors name the offending key, with its line and column
+        let mut config: Self =
+            toml::from_str(&text).with_context(|| format!("Invalid config file {:?}", path))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        config.documents.dir = base.join(&config.documents.dir);
+        config.index.dir = base.join(&config.index.dir);
+
+        Ok(config)
+    }
+
+    /// Sets the setting named by a dotted key such as `chunking.size`; lists are comma-separated
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "documents.dir" => self.documents.dir = PathBuf::from(value),
+            "documents.include" => self.documents.include = list(value),
+            "documents.exclude" => self.documents.exclude = list(value),
+            "index.dir" => self.index.dir = PathBuf::from(value),
+            "chunking.size" => self.chunking.size = parse(key, value)?,
+            "chunking.overlap" => self.chunking.overlap = parse(key, value)?,
+            "chunking.unit" => self.chunking.unit = parse(key, value)?,
+            "chunking.strategy" => self.chunking.strategy = parse(key, value)?,
+            "embedding.provider" => self.embedding.provider = parse(key, value)?,
+            "embedding.model" => self.embedding.model = Some(value.to_string()),
+            "embedding.base_url" => self.embedding.base_url = Some(value.to_string()),
+            "embedding.api_version" => self.embedding.api_version = Some(value.to_string()),
+            "embedding.api_key_env" => self.embedding.api_key_env = Some(value.to_string()),
+            "embedding.headers" => self.embedding.headers = headers(key, value)?,
+            "completion.provider" => self.completion.provider = parse(key, value)?,
+            "completion.model" => self.completion.model = Some(value.to_string()),
+            "completion.base_url" => self.completion.base_url = Some(value.to_string()),
+            "completion.api_version" => self.completion.api_version = Some(value.to_string()),
+            "completion.api_key_env" => self.completion.api_key_env = Some(value.to_string()),
+            "completion.headers" => self.completion.headers = headers(key, value)?,
+            "completion.preamble" => self.completion.preamble = value.to_string(),
+            "completion.stream" => self.completion.stream = parse(key, value)?,
+            "retrieval.top_k" => self.retrieval.top_k = parse(key, value)?,
+            "retrieval.context_tokens" => self.retrieval.context_tokens = optional(key, value)?,
+            "retrieval.mode" => self.retrieval.mode = parse(key, value)?,
+            "retrieval.rrf_k" => self.retrieval.rrf_k = parse(key, value)?,
+            "retrieval.rerank" => self.retrieval.rerank = parse(key, value)?,
+            "retrieval.candidates" => self.retrieval.candidates = parse(key, value)?,
+            "retrieval.mmr" => self.retrieval.mmr = parse(key, value)?,
+            "retrieval.mmr_lambda" => self.retrieval.mmr_lambda = parse(key, value)?,
+            "retrieval.min_score" => self.retrieval.min_score = optional(key, value)?,
+            "retrieval.no_context" => self.retrieval.no_context = parse(key, value)?,
+            "memory.condense" => self.memory.condense = parse(key, value)?,
+            "memory.history_tokens" => self.memory.history_tokens = parse(key, value)?,
+            "server.addr" => self.server.addr = parse(key, value)?,
+            // One header at a time, e.g. completion.headers.X-Team=search
+            _ if key.starts_with("embedding.headers.") => {
+                let name = &key["embedding.headers.".len()..];
+                self.embedding
+                    .headers
+                    .insert(name.to_string(), value.to_string());
+            }
+            _ if key.starts_with("completion.headers.") => {
+                let name = &key["completion.headers.".len()..];
+                self.completion
+                    .headers
+                    .insert(name.to_string(), value.to_string());
+            }
+            _ => anyhow::bail!("Unknown config key {key:?}"),
+        }
+        Ok(())
+    }
+
+    /// Checks settings that parse but can't work together
+    pub fn validate(&self) -> Result<()> {
+        let chunking = &self.chunking;
+        if chunking.size == 0 {
+            anyhow::bail!("chunking.size must be greater than 0");
+        }
+        if chunking.overlap >= chunking.size {
+            anyhow::bail!(
+                "chunking.overlap ({}) must be smaller than chunking.size ({})",
+                chunking.overlap,
+                chunking.size
+            );
+        }
+        if chunking.unit == ChunkUnit::Tokens && chunking.size > MAX_EMBEDDING_TOKENS {
+            anyhow::bail!(
+                "chunking.size ({} tokens) exceeds the embedding model's limit of {} tokens",
+                chunking.size,
+                MAX_EMBEDDING_TOKENS
+            );
+        }
+        if self.embedding.model().trim().is_empty() {
+            anyhow::bail!("embedding.model must not be empty");
+        }
+        if self.embedding.provider == Provider::Hashed {
+            hashed::EmbeddingModel::from_name(self.embedding.model())
+                .context("Invalid value for embedding.model")?;
+        }
+        if self.completion.provider == Provider::Hashed {
+            anyhow::bail!(
+                "completion.provider can't be \"hashed\", which only provides embeddings"
+            );
+        }
+        if self.completion.model().trim().is_empty() {
+            anyhow::bail!("completion.model must not be empty");
+        }
+        for (key, base_url) in [
+            ("embedding.base_url", &self.embedding.base_url),
+            ("completion.base_url", &self.completion.base_url),
+        ] {
+            if let Some(url) = base_url {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    anyhow::bail!("{key} must start with http:// or https://, got {url:?}");
+                }
+            }
+        }
+        if self.retrieval.top_k == 0 {
+            anyhow::bail!("retrieval.top_k must be greater than 0");
+        }
+        if self.retrieval.candidates == 0 {
+            anyhow::bail!("retrieval.candidates must be greater than 0");
+        }
+        if let Some(min_score) = self.retrieval.min_score {
+            if !min_score.is_finite() {
+                anyhow::bail!("retrieval.min_score must be a number, got {min_score}");
+            }
+            // Only cosine similarity has a fixed scale; BM25 scores are unbounded and fused
+            // rank scores sit around 0.02 whatever the chunks say
+            if self.retrieval.mode != SearchMode::Vector {
+                anyhow::bail!(
+                    "retrieval.min_score only applies to retrieval.mode = \"vector\", got \"{}\"",
+                    self.retrieval.mode
+                );
+            }
+        }
+        if self.retrieval.rrf_k.is_nan() || self.retrieval.rrf_k < 0.0 {
+            anyhow::bail!("retrieval.rrf_k must not be negative");
+        }
+        if !(0.0..=1.0).contains(&self.retrieval.mmr_lambda) {
+            anyhow::bail!(
+                "retrieval.mmr_lambda must be between 0 and 1, got {}",
+                self.retrieval.mmr_lambda
+            );
+        }
+
+        self.discovery_options()?;
+        Ok(())
+    }
+
+    pub fn chunk_options(&self) -> ChunkOptions {
+        ChunkOptions {
+            window: Window {
+                size: self.chunking.size,
+                overlap: self.chunking.overlap,
+                unit: self.chunking.unit,
+            },
+            strategy: self.chunking.strategy,
+        }
+    }
+
+    pub fn discovery_options(&self) -> Result<DiscoveryOptions> {
+        DiscoveryOptions::new(&self.documents.include, &self.documents.exclude)
+            .context("Invalid glob in documents.include or documents.exclude")
+    }
+
+    pub fn retrieval_options(&self) -> RetrievalOptions {
+        RetrievalOptions {
+            context_tokens: self.retrieval.context_tokens,
+            min_score: self.retrieval.min_score,
+            candidates: self.retrieval.candidates,
+        }
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid value for {key}: {value:?} ({e})"))
+}
+
+// An optional setting; empty or "none" unsets it, e.g. to lift a limit set in the config file
+fn optional<T>(key: &str, value: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match value.trim() {
+        "" | "none" => Ok(None),
+        _ => parse(key, value).map(Some),
+    }
+}
+
+// "Name=value,Other=value"
+fn headers(key: &str, value: &str) -> Result<BTreeMap<String, String>> {
+    list(value)
+        .iter()
+        .map(|header| {
+            let (name, value) = header.split_once('=').with_context(|| {
+                format!("Invalid value for {key}: expected Name=value, got {header:?}")
+            })?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Deserializes a string setting through its `FromStr` implementation
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Full error message, with its context chain
+    fn error(result: Result<impl fmt::Debug>) -> String {
+        format!("{:#}", result.unwrap_err())
+    }
+
+    fn file(contents: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("rag.toml");
+        std::fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let (dir, path) = file(
+            "[chunking]\nsize = 500\noverlap = 50\n\n\
+             [retrieval]\ntop_k = 2\nmode = \"hybrid\"\n\n\
+             [completion.headers]\nX-Team = \"docs\"\n",
+        );
+        let env = BTreeMap::from([
+            ("RAG_TOP_K".to_string(), "5".to_string()),
+            ("RAG_CHUNK_OVERLAP".to_string(), "60".to_string()),
+        ]);
+        let overrides = [
+            ("retrieval.top_k".to_string(), "7".to_string()),
+            ("completion.headers.X-Env".to_string(), "prod".to_string()),
+        ];
+        let mut config = Config::from_file(&path).unwrap();
+        config.apply_overrides(&env, &overrides).unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.chunking.size, 500);
+        assert_eq!(config.chunking.overlap, 60);
+        assert_eq!(config.retrieval.top_k, 7);
+        assert_eq!(config.retrieval.mode, SearchMode::Hybrid);
+        assert_eq!(config.retrieval.candidates, DEFAULT_CANDIDATES);
+        let headers: Vec<(&str, &str)> = config
+            .completion
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(headers, [("X-Env", "prod"), ("X-Team", "docs")]);
+        // Relative to the config file, not the working directory
+        assert_eq!(config.documents.dir, dir.path().join("documents"));
+    }
+
+    #[test]
+    fn file_errors_name_the_key() {
+        let (_dir, path) = file("[retrieval]\ntop-k = 3\n");
+        let message = error(Config::from_file(&path));
+        assert!(message.contains("unknown field `top-k`"), "{message}");
+
+        let (_dir, path) = file("[chunking]\nstrategy = \"lines\"\n");
+        let message = error(Config::from_file(&path));
+        assert!(message.contains("strategy"), "{message}");
+        assert!(
+            message.contains("Unknown chunk strategy \"lines\""),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn optional_settings_can_be_unset() {
+        let (_dir, path) = file("[retrieval]\ncontext_tokens = 3000\nmin_score = 0.8\n");
+        let mut config = Config::from_file(&path).unwrap();
+        assert_eq!(config.retrieval.context_tokens, Some(3000));
+        assert_eq!(config.retrieval.min_score, Some(0.8));
+
+        let env = BTreeMap::from([("RAG_CONTEXT_TOKENS".to_string(), "".to_string())]);
+        let overrides = [("retrieval.min_score".to_string(), " none ".to_string())];
+        config.apply_overrides(&env, &overrides).unwrap();
+        assert_eq!(config.retrieval.context_tokens, None);
+        assert_eq!(config.retrieval.min_score, None);
+
+        config.set("retrieval.context_tokens", "2000").unwrap();
+        assert_eq!(config.retrieval.context_tokens, Some(2000));
+        assert_eq!(
+            error(config.set("retrieval.min_score", "high")),
+            "Invalid value for retrieval.min_score: \"high\" (invalid float literal)"
+        );
+    }
+
+    #[test]
+    fn overrides_are_checked() {
+        let mut config = Config::default();
+        assert_eq!(
+            error(config.set("chunking.size", "big")),
+            "Invalid value for chunking.size: \"big\" (invalid digit found in string)"
+        );
+        assert_eq!(
+            error(config.set("retrieval.topk", "3")),
+            "Unknown config key \"retrieval.topk\""
+        );
+        assert!(
+            error(config.set("embedding.headers", "X-Team")).starts_with(
+                "Invalid value for embedding.headers: expected Name=value, got \"X-Team\""
+            )
+        );
+
+        config
+            .set("embedding.headers", "X-Team = docs, X-Env=prod")
+            .unwrap();
+        config.set("embedding.headers.X-Env", "test").unwrap();
+        assert_eq!(config.embedding.headers.len(), 2);
+        assert_eq!(config.embedding.headers["X-Env"], "test");
+    }
+
+    #[test]
+    fn validation_names_the_bad_setting() {
+        let invalid = |key: &str, value: &str| {
+            let mut config = Config::default();
+            config.set(key, value).unwrap();
+            error(config.validate())
+        };
+
+        assert_eq!(
+            invalid("chunking.overlap", "2000"),
+            "chunking.overlap (2000) must be smaller than chunking.size (2000)"
+        );
+        assert_eq!(
+            invalid("retrieval.candidates", "0"),
+            "retrieval.candidates must be greater than 0"
+        );
+        assert_eq!(
+            invalid("retrieval.min_score", "NaN"),
+            "retrieval.min_score must be a number, got NaN"
+        );
+        let mut config = Config::default();
+        config.set("retrieval.min_score", "0.3").unwrap();
+        config.set("retrieval.mode", "hybrid").unwrap();
+        assert_eq!(
+            error(config.validate()),
+            "retrieval.min_score only applies to retrieval.mode = \"vector\", got \"hybrid\""
+        );
+        assert_eq!(
+            invalid("retrieval.mmr_lambda", "1.5"),
+            "retrieval.mmr_lambda must be between 0 and 1, got 1.5"
+        );
+        assert!(invalid("completion.base_url", "localhost:8080")
+            .starts_with("completion.base_url must start with http://"));
+        assert!(invalid("documents.include", "[").starts_with("Invalid glob in documents.include"));
+        assert!(Config::default().validate().is_ok());
+    }
+}
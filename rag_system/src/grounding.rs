@@ -0,0 +1,113 @@
+// What the agent does when retrieval finds nothing relevant enough to answer from, so answers
+// stay grounded in the documents instead of the model's general knowledge
+use anyhow::Result;
+use futures::stream;
+use rig::completion::{self, CompletionError, CompletionRequest, ModelChoice};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::streaming::{StreamingCompletionModel, TokenStream};
+
+// The reply when the model isn't asked at all
+pub const REFUSAL: &str =
+    "I can't answer that: nothing in the documents is relevant enough to the question.";
+
+const NOT_FOUND_INSTRUCTIONS: &str = "No document relevant to this question was found. Tell the user that the documents don't cover it. Don't answer from general knowledge.";
+
+const FALLBACK_INSTRUCTIONS: &str = "No document relevant to this question was found. Answer from general knowledge, say that the answer isn't based on the documents, and don't cite anything.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoContext {
+    // Reply with a fixed refusal without calling the model
+    Refuse,
+    // Have the model say the documents don't cover the question
+    NotFound,
+    // Let the model answer from general knowledge, flagged as such
+    Fallback,
+}
+
+impl FromStr for NoContext {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "refuse" => Ok(NoContext::Refuse),
+            "not_found" => Ok(NoContext::NotFound),
+            "fallback" => Ok(NoContext::Fallback),
+            _ => anyhow::bail!(
+                "Unknown no-context behavior {value:?}, expected \"refuse\", \"not_found\" or \"fallback\""
+            ),
+        }
+    }
+}
+
+impl fmt::Display for NoContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoContext::Refuse => write!(f, "refuse"),
+            NoContext::NotFound => write!(f, "not_found"),
+            NoContext::Fallback => write!(f, "fallback"),
+        }
+    }
+}
+
+/// Wraps the agent's chat model, applying `NoContext` to requests that arrive without documents
+#[derive(Clone)]
+pub struct Grounded<M> {
+    model: M,
+    no_context: NoContext,
+}
+
+impl<M> Grounded<M> {
+    pub fn new(model: M, no_context: NoContext) -> Self {
+        Self { model, no_context }
+    }
+
+    // The request to send the model, or None if it shouldn't be asked
+    fn ground(&self, mut request: CompletionRequest) -> Option<CompletionRequest> {
+        if request.documents.is_empty() {
+            let instructions = match self.no_context {
+                NoContext::Refuse => return None,
+                NoContext::NotFound => NOT_FOUND_INSTRUCTIONS,
+                NoContext::Fallback => FALLBACK_INSTRUCTIONS,
+            };
+            request.preamble = Some(match request.preamble {
+                Some(preamble) => format!("{preamble} {instructions}"),
+                None => instructions.to_string(),
+            });
+        }
+        Some(request)
+    }
+}
+
+impl<M: completion::CompletionModel> completion::CompletionModel for Grounded<M> {
+    // None when the model wasn't asked
+    type Response = Option<M::Response>;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<Self::Response>, CompletionError> {
+        let Some(request) = self.ground(request) else {
+            return Ok(completion::CompletionResponse {
+                choice: ModelChoice::Message(REFUSAL.to_string()),
+                raw_response: None,
+            });
+        };
+
+        let response = self.model.completion(request).await?;
+        Ok(completion::CompletionResponse {
+            choice: response.choice,
+            raw_response: Some(response.raw_response),
+        })
+    }
+}
+
+impl<M: StreamingCompletionModel> StreamingCompletionModel for Grounded<M> {
+    async fn stream(&self, request: CompletionRequest) -> Result<TokenStream> {
+        match self.ground(request) {
+            Some(request) => self.model.stream(request).await,
+            None => Ok(Box::pin(stream::iter([Ok(REFUSAL.to_string())]))),
+        }
+    }
+}
@@ -0,0 +1,354 @@
+// Incremental index updates: only new or changed files are chunked and embedded again
+use anyhow::{Context, Result};
+use rig::embeddings::{EmbeddingModel, EmbeddingsBuilder};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::chunking::ChunkOptions;
+use crate::ingest::{self, DiscoveryOptions};
+use crate::loaders;
+use crate::store::{SourceRecord, StoredEntry, StoredIndex};
+
+/// How the documents directory differed from the previous index
+#[derive(Debug, Default)]
+pub struct IndexUpdate {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    // Unchanged files whose modification time or size did, so their records need saving for
+    // the next run to skip hashing them
+    pub touched: usize,
+}
+
+impl IndexUpdate {
+    /// True when the index didn't change and doesn't need to be saved again
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.changed == 0 && self.removed == 0 && self.touched == 0
+    }
+}
+
+impl std::fmt::Display for IndexUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} changed, {} removed, {} unchanged",
+            self.added, self.changed, self.removed, self.unchanged
+        )
+    }
+}
+
+/// Brings `previous` up to date with the files under `documents_dir`.
+///
+/// Files whose size and modification time match the previous run are reused without being read;
+/// otherwise the content hash decides. Chunks of deleted files are dropped, and an index whose
+/// files are all gone becomes empty; only a first build without any files is an error.
+pub async fn update_index<M: EmbeddingModel>(
+    model: M,
+    chunk_options: &ChunkOptions,
+    discovery: &DiscoveryOptions,
+    documents_dir: &Path,
+    previous: StoredIndex,
+) -> Result<(StoredIndex, IndexUpdate)> {
+    // Find every supported file under the documents directory matching the include/exclude globs
+    let sources = ingest::discover(documents_dir, discovery)?;
+
+    if sources.is_empty() && previous.sources.is_empty() {
+        anyhow::bail!("No documents found in {:?}", documents_dir);
+    }
+
+    let mut old_sources: HashMap<String, SourceRecord> = previous
+        .sources
+        .into_iter()
+        .map(|record| (record.id.clone(), record))
+        .collect();
+    let mut old_entries: HashMap<String, StoredEntry> = previous
+        .entries
+        .into_iter()
+        .map(|entry| (entry.document.id.clone(), entry))
+        .collect();
+
+    let mut index = StoredIndex::empty(&previous.embedding_model, &previous.chunking);
+    let mut update = IndexUpdate::default();
+    let mut builder = EmbeddingsBuilder::new(model);
+    let chunker = chunk_options.chunker();
+
+    for source in sources {
+        let metadata = std::fs::metadata(&source.path)
+            .with_context(|| format!("Failed to read metadata of {}", source.id))?;
+        let modified_ns = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        let size = metadata.len();
+
+        let record = old_sources.remove(&source.id);
+
+        // Same size and timestamp: trust the previous hash instead of reading the whole file
+        let hash = match &record {
+            Some(record) if record.modified_ns == modified_ns && record.size == size => {
+                record.hash.clone()
+            }
+            _ => hash_file(&source.path)?,
+        };
+
+        match record {
+            // Content unchanged: keep the existing chunks and their embeddings
+            Some(record) if record.hash == hash => {
+                if record.modified_ns != modified_ns || record.size != size {
+                    update.touched += 1;
+                }
+                index
+                    .entries
+                    .extend(record.chunks.iter().filter_map(|id| old_entries.remove(id)));
+                index.sources.push(SourceRecord {
+                    modified_ns,
+                    size,
+                    ..record
+                });
+                update.unchanged += 1;
+            }
+            // New or modified file: chunk it and queue the chunks for embedding
+            record => {
+                if record.is_some() {
+                    update.changed += 1;
+                } else {
+                    update.added += 1;
+                }
+
+                let documents = loaders::load_documents(&source, chunker.as_ref())
+                    .with_context(|| format!("Failed to load {}", source.id))?;
+                // Kept as a source with no chunks, so it isn't loaded again until it changes
+                if documents.is_empty() {
+                    eprintln!("Skipping {}: no text found", source.id);
+                }
+
+                index.sources.push(SourceRecord {
+                    hash,
+                    modified_ns,
+                    size,
+                    chunks: documents
+                        .iter()
+                        .map(|document| document.id.clone())
+                        .collect(),
+                    id: source.id,
+                });
+                builder = builder.documents(documents)?;
+            }
+        }
+    }
+
+    // Whatever is left was not found in the documents directory anymore
+    update.removed = old_sources.len();
+
+    if update.added > 0 || update.changed > 0 {
+        for (document, embeddings) in builder.build().await? {
+            index.entries.push(StoredEntry::new(document, &embeddings));
+        }
+        eprintln!("Successfully generated embeddings");
+    }
+
+    Ok((index, update))
+}
+
+// Hex-encoded SHA-256 of a file's contents
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rig::embeddings::{Embedding, EmbeddingError};
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    use crate::providers::hashed;
+
+    const BAKING: &str = "Sourdough bread needs a long proof before baking.";
+    const SAILING: &str = "Reef the mainsail early if the wind picks up.";
+
+    // Hashed embeddings that count how many texts they were asked to embed
+    #[derive(Clone, Default)]
+    struct Counting {
+        model: hashed::EmbeddingModel,
+        texts: Arc<AtomicUsize>,
+    }
+
+    impl EmbeddingModel for Counting {
+        const MAX_DOCUMENTS: usize = 1024;
+
+        fn ndims(&self) -> usize {
+            self.model.ndims()
+        }
+
+        async fn embed_texts(
+            &self,
+            texts: impl IntoIterator<Item = String> + Send,
+        ) -> Result<Vec<Embedding>, EmbeddingError> {
+            let texts: Vec<String> = texts.into_iter().collect();
+            self.texts.fetch_add(texts.len(), Ordering::SeqCst);
+            self.model.embed_texts(texts).await
+        }
+    }
+
+    fn setup() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("baking.md"), BAKING).unwrap();
+        fs::write(dir.path().join("sailing.txt"), SAILING).unwrap();
+        dir
+    }
+
+    // Updates `previous` from `dir`, returning the number of texts embedded along the way
+    async fn update(dir: &TempDir, previous: StoredIndex) -> (StoredIndex, IndexUpdate, usize) {
+        let model = Counting::default();
+        let (index, update) = update_index(
+            model.clone(),
+            &ChunkOptions::default(),
+            &DiscoveryOptions::new(&[], &[]).unwrap(),
+            dir.path(),
+            previous,
+        )
+        .await
+        .unwrap();
+        (index, update, model.texts.load(Ordering::SeqCst))
+    }
+
+    async fn first_index(dir: &TempDir) -> StoredIndex {
+        let (index, update, embedded) = update(dir, StoredIndex::empty("hashed", "default")).await;
+        assert_eq!((update.added, embedded), (2, 2));
+        index
+    }
+
+    fn contents(index: &StoredIndex, source: &str) -> Vec<String> {
+        index
+            .entries
+            .iter()
+            .filter(|entry| entry.document.source == source)
+            .map(|entry| entry.document.content.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn touched_files_are_not_embedded_again() {
+        let dir = setup();
+        let previous = first_index(&dir).await;
+
+        let file = fs::File::options()
+            .write(true)
+            .open(dir.path().join("baking.md"))
+            .unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+
+        let (index, update, embedded) = update(&dir, previous).await;
+
+        assert_eq!(update.unchanged, 2);
+        assert_eq!(embedded, 0);
+        assert_eq!(index.entries.len(), 2);
+        // The new timestamp has to be saved, or every later run hashes the file again
+        assert_eq!(update.touched, 1);
+        assert!(!update.is_empty());
+    }
+
+    #[tokio::test]
+    async fn touched_timestamps_are_trusted_on_the_next_run() {
+        let dir = setup();
+        let previous = first_index(&dir).await;
+        let path = dir.path().join("baking.md");
+        let touched = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(touched)
+            .unwrap();
+        let (previous, _, _) = update(&dir, previous).await;
+
+        // Same size and timestamp as recorded, but other content: only hashing would notice
+        fs::write(&path, BAKING.replace("proof", "prove")).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(touched)
+            .unwrap();
+        let (_, update, embedded) = update(&dir, previous).await;
+
+        assert!(update.is_empty());
+        assert_eq!(update.unchanged, 2);
+        assert_eq!(embedded, 0);
+    }
+
+    #[tokio::test]
+    async fn changed_files_replace_only_their_chunks() {
+        let dir = setup();
+        let previous = first_index(&dir).await;
+        let sailing = contents(&previous, "sailing.txt");
+
+        fs::write(dir.path().join("baking.md"), "Rye bread rises faster.").unwrap();
+        let (index, update, embedded) = update(&dir, previous).await;
+
+        assert_eq!((update.changed, update.unchanged), (1, 1));
+        assert_eq!(embedded, 1);
+        assert_eq!(contents(&index, "baking.md"), ["Rye bread rises faster."]);
+        assert_eq!(contents(&index, "sailing.txt"), sailing);
+    }
+
+    #[tokio::test]
+    async fn deleted_files_lose_their_chunks() {
+        let dir = setup();
+        let previous = first_index(&dir).await;
+
+        fs::remove_file(dir.path().join("sailing.txt")).unwrap();
+        let (index, update, embedded) = update(&dir, previous).await;
+
+        assert_eq!((update.removed, update.unchanged), (1, 1));
+        assert_eq!(embedded, 0);
+        assert!(contents(&index, "sailing.txt").is_empty());
+        let ids: Vec<&str> = index
+            .sources
+            .iter()
+            .map(|source| source.id.as_str())
+            .collect();
+        assert_eq!(ids, ["baking.md"]);
+    }
+    #[tokio::test]
+    async fn deleting_every_file_empties_the_index() {
+        let dir = setup();
+        let previous = first_index(&dir).await;
+
+        fs::remove_file(dir.path().join("baking.md")).unwrap();
+        fs::remove_file(dir.path().join("sailing.txt")).unwrap();
+        let (index, update, _) = update(&dir, previous).await;
+
+        assert_eq!(update.removed, 2);
+        assert!(index.sources.is_empty());
+        assert!(index.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn first_build_needs_documents() {
+        let dir = TempDir::new().unwrap();
+        let result = update_index(
+            Counting::default(),
+            &ChunkOptions::default(),
+            &DiscoveryOptions::new(&[], &[]).unwrap(),
+            dir.path(),
+            StoredIndex::empty("hashed", "default"),
+        )
+        .await;
+
+        assert!(format!("{:#}", result.unwrap_err()).starts_with("No documents found"));
+    }
+}
@@ -0,0 +1,206 @@
+// Discovery of the source files that make up the searchable corpus
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use std::path::{Path, PathBuf};
+
+use crate::loaders::Format;
+
+// Files picked up when no include globs are configured
+pub const DEFAULT_INCLUDE: &[&str] = &[
+    "**/*.pdf",
+    "**/*.txt",
+    "**/*.md",
+    "**/*.markdown",
+    "**/*.html",
+    "**/*.htm",
+];
+
+// `*` stays inside one directory level, `**` crosses directories
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Include/exclude globs, matched against paths relative to the documents directory
+#[derive(Clone, Debug)]
+pub struct DiscoveryOptions {
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+}
+
+impl DiscoveryOptions {
+    /// Builds the options from lists of glob strings
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let compile = |globs: &[String]| -> Result<Vec<Pattern>> {
+            globs
+                .iter()
+                .map(|glob| Pattern::new(glob).with_context(|| format!("Invalid glob: {glob}")))
+                .collect()
+        };
+
+        let mut include = compile(include)?;
+        if include.is_empty() {
+            for glob in DEFAULT_INCLUDE {
+                include.push(Pattern::new(glob)?);
+            }
+        }
+
+        Ok(Self {
+            include,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    fn matches(&self, relative: &str) -> bool {
+        self.include
+            .iter()
+            .any(|pattern| pattern.matches_with(relative, MATCH_OPTIONS))
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches_with(relative, MATCH_OPTIONS))
+    }
+}
+
+/// A file selected for ingestion
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    // Relative path with `/` separators, e.g. "papers/01.pdf"; stable across machines
+    pub id: String,
+}
+
+/// Recursively collects every file under `root` accepted by `options`, sorted by id
+pub fn discover(root: &Path, options: &DiscoveryOptions) -> Result<Vec<SourceFile>> {
+    let mut sources = Vec::new();
+    walk(root, root, options, &mut sources)?;
+    sources.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(sources)
+}
+
+fn walk(
+    root: &Path,
+    dir: &Path,
+    options: &DiscoveryOptions,
+    sources: &mut Vec<SourceFile>,
+) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read directory {:?}", dir))?;
+
+    for entry in entries {
+        let path = entry?.path();
+
+        // Symlinked directories aren't followed, since one could lead back to its own parent
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("Failed to read metadata of {:?}", path))?;
+        if metadata.is_dir() {
+            walk(root, &path, options, sources)?;
+            continue;
+        }
+        if metadata.is_symlink() && path.is_dir() {
+            continue;
+        }
+
+        // Files of unsupported formats are skipped even when a glob matches them
+        let id = document_id(root, &path)?;
+        if options.matches(&id) && Format::from_path(&path).is_some() {
+            sources.push(SourceFile { path, id });
+        }
+    }
+
+    Ok(())
+}
+
+/// Derives a document id from a file path: its path relative to `root`, joined with `/`
+pub fn document_id(root: &Path, path: &Path) -> Result<String> {
+    let relative = path
+        .strip_prefix(root)
+        .with_context(|| format!("{:?} is not inside {:?}", path, root))?;
+
+    Ok(relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    // A documents directory containing an empty file at each of `paths`
+    fn documents(paths: &[&str]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for path in paths {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    fn ids(dir: &TempDir, include: &[&str], exclude: &[&str]) -> Vec<String> {
+        let globs = |globs: &[&str]| {
+            globs
+                .iter()
+                .map(|glob| glob.to_string())
+                .collect::<Vec<_>>()
+        };
+        let options = DiscoveryOptions::new(&globs(include), &globs(exclude)).unwrap();
+        discover(dir.path(), &options)
+            .unwrap()
+            .into_iter()
+            .map(|source| source.id)
+            .collect()
+    }
+
+    #[test]
+    fn nested_files_get_relative_ids() {
+        let dir = documents(&["b.md", "papers/01.pdf", "papers/2024/q1.txt", "notes.docx"]);
+
+        assert_eq!(
+            ids(&dir, &[], &[]),
+            ["b.md", "papers/01.pdf", "papers/2024/q1.txt"]
+        );
+    }
+
+    #[test]
+    fn globs_include_and_exclude_by_relative_path() {
+        let dir = documents(&[
+            "a.md",
+            "papers/01.pdf",
+            "papers/draft.pdf",
+            "papers/old/02.pdf",
+        ]);
+
+        // `*` stays within one directory level
+        assert_eq!(
+            ids(&dir, &["papers/*.pdf"], &[]),
+            ["papers/01.pdf", "papers/draft.pdf"]
+        );
+        assert_eq!(
+            ids(&dir, &["**/*.pdf"], &["**/draft.pdf", "papers/old/**"]),
+            ["papers/01.pdf"]
+        );
+        // Globs can't pull in unsupported formats
+        assert_eq!(ids(&dir, &["**/*"], &["**/*.pdf"]), ["a.md"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_not_followed() {
+        let dir = documents(&["guide/intro.md"]);
+        // A loop back to the documents directory itself
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("guide/loop")).unwrap();
+        std::os::unix::fs::symlink(
+            dir.path().join("guide/intro.md"),
+            dir.path().join("intro-link.md"),
+        )
+        .unwrap();
+
+        assert_eq!(ids(&dir, &[], &[]), ["guide/intro.md", "intro-link.md"]);
+    }
+}
@@ -0,0 +1,39 @@
+// The RAG pipeline as a library, so the binary and the integration tests share it
+use rig::Embed;
+use serde::{Deserialize, Serialize};        // For serialization and deserialization
+
+pub mod bm25;                               // Keyword search over the chunks
+pub mod chat;                               // Interactive chat with a sources footer
+pub mod chunking;                           // Splitting text into overlapping chunks
+pub mod citations;                          // Numbered citations for retrieved chunks
+pub mod config;                             // rag.toml settings with env/CLI overrides
+pub mod grounding;                          // Answering when no chunk is relevant
+pub mod indexer;                            // Incremental chunking and embedding
+pub mod ingest;                             // Discovery of the files to index
+pub mod loaders;                            // PDF, text, Markdown and HTML loading
+pub mod memory;                             // Conversation history and follow-up questions
+pub mod mmr;                                // Diversifying the retrieved chunks
+pub mod pipeline;                           // Building the index and the RAG agent
+pub mod providers;                          // OpenAI, Ollama, hashed and mock models
+pub mod rerank;                             // Reordering candidates before they reach the agent
+pub mod retrieval;                          // Token budget for retrieved context
+pub mod search;                             // Vector, keyword and hybrid retrieval
+pub mod server;                             // HTTP API over the agent and the index
+pub mod store;                              // Saving/loading the embedded index
+pub mod streaming;                          // Answers streamed as they're written
+pub mod tokens;                             // cl100k_base token counting
+#[cfg(test)]
+mod testing;                                // Test doubles and fixtures for the unit tests
+
+#[derive(Embed, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]        // Define a struct for documents with embedding capabilities
+pub struct Document {
+    pub id: String,
+    // Metadata below is stored with the chunk and shown to the agent, but not embedded
+    pub source: String,       // Path of the source file relative to the documents directory
+    pub page_start: Option<usize>, // First page (1-based) the chunk's text comes from, for PDFs
+    pub page_end: Option<usize>,   // Last page the chunk's text comes from, for PDFs
+    pub section: Option<String>,   // Heading path such as "Setup > Installing", for Markdown and HTML
+    pub ordinal: usize,       // Position of the chunk within its source file
+    #[embed]
+    pub content: String,
+}
@@ -0,0 +1,630 @@
+// Reading source files of every supported format into chunked `Document`s
+use anyhow::{Context, Result};
+use rig::loaders::PdfFileLoader;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::path::Path;
+
+use crate::chunking::Chunker;
+use crate::ingest::SourceFile;
+use crate::Document;
+
+/// File formats that can be ingested, recognized by extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Pdf,
+    Text,
+    Markdown,
+    Html,
+}
+
+impl Format {
+    /// The format of `path`, or `None` if it isn't supported
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "pdf" => Some(Format::Pdf),
+            "txt" | "text" => Some(Format::Text),
+            "md" | "markdown" => Some(Format::Markdown),
+            "html" | "htm" => Some(Format::Html),
+            _ => None,
+        }
+    }
+}
+
+/// Text extracted from a source file, plus the structure chunking should respect
+#[derive(Debug, Default)]
+pub struct LoadedText {
+    pub text: String,
+    // Byte offset where each page starts and its 1-based number; empty for formats without pages
+    pub pages: Vec<(usize, usize)>,
+    // Byte offset where each section starts and its heading path; chunks never cross sections
+    pub sections: Vec<(usize, String)>,
+}
+
+impl LoadedText {
+    // Page containing the byte at `offset`
+    fn page_at(&self, offset: usize) -> Option<usize> {
+        let next = self.pages.partition_point(|(start, _)| *start <= offset);
+        self.pages.get(next.checked_sub(1)?).map(|(_, page)| *page)
+    }
+}
+
+/// Loads `source` and cuts it into chunks with `chunker`; a file without any text has none
+pub fn load_documents(source: &SourceFile, chunker: &dyn Chunker) -> Result<Vec<Document>> {
+    let format = Format::from_path(&source.path)
+        .with_context(|| format!("Unsupported file type: {}", source.id))?;
+
+    let loaded = match format {
+        Format::Pdf => load_pdf(&source.path)?,
+        Format::Text => load_text(&source.path)?,
+        Format::Markdown => load_markdown(&source.path)?,
+        Format::Html => load_html(&source.path)?,
+    };
+
+    Ok(chunk_text(&source.id, &loaded, chunker))
+}
+
+// Chunks the loaded text of the source `id` section by section, giving each chunk the pages its
+// text comes from
+fn chunk_text(id: &str, loaded: &LoadedText, chunker: &dyn Chunker) -> Vec<Document> {
+    // Text before the first heading (or the whole text, without headings) is its own section
+    let mut sections = loaded.sections.clone();
+    if sections.first().is_none_or(|(start, _)| *start > 0) {
+        sections.insert(0, (0, String::new()));
+    }
+
+    let mut documents = Vec::new();
+    for (i, (start, heading)) in sections.iter().enumerate() {
+        let end = sections
+            .get(i + 1)
+            .map_or(loaded.text.len(), |(next, _)| *next);
+
+        // Split into overlapping windows so facts on a chunk boundary appear whole in one of them
+        for chunk in chunker.chunk(&loaded.text[*start..end]) {
+            let ordinal = documents.len();
+            documents.push(Document {
+                id: format!("{}#{}", id, ordinal),
+                source: id.to_string(),
+                page_start: loaded.page_at(start + chunk.span.start),
+                page_end: loaded.page_at((start + chunk.span.end).saturating_sub(1)),
+                section: (!heading.is_empty()).then(|| heading.clone()),
+                ordinal,
+                content: chunk.text,
+            });
+        }
+    }
+
+    documents
+}
+
+// Text of all pages separated by blank lines, and the offset where each page starts
+fn load_pdf(path: &Path) -> Result<LoadedText> {
+    let mut loaded = LoadedText::default();
+
+    // The loader takes a glob, so the path has to be text; escape it so file names containing
+    // glob characters still load
+    let path = path
+        .to_str()
+        .with_context(|| format!("PDF path {:?} is not valid UTF-8", path))?;
+    let pattern = glob::Pattern::escape(path);
+    for pdf in PdfFileLoader::with_glob(&pattern)?.load() {
+        let pdf = pdf?;
+        for page in pdf.get_pages().into_keys() {
+            // One unreadable page shouldn't cost the rest of the document
+            let text = match pdf.extract_text(&[page]) {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("Skipping page {page} of {path}: {e}");
+                    continue;
+                }
+            };
+            loaded.pages.push((loaded.text.len(), page as usize));
+            loaded.text.push_str(&text);
+            loaded.text.push_str("\n\n");
+        }
+    }
+
+    Ok(loaded)
+}
+
+fn read_text(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    // Tolerate the odd invalid byte rather than rejecting the whole file
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn load_text(path: &Path) -> Result<LoadedText> {
+    Ok(LoadedText {
+        text: read_text(path)?,
+        ..Default::default()
+    })
+}
+
+// The headings enclosing the current position, used to name sections like "Setup > Installing"
+#[derive(Default)]
+struct Outline(Vec<(usize, String)>);
+
+impl Outline {
+    // Enters a heading of `level` (1 for the top level) and returns the new heading path
+    fn enter(&mut self, level: usize, title: &str) -> String {
+        self.0.retain(|(parent, _)| *parent < level);
+        self.0.push((level, title.to_string()));
+
+        let path: Vec<&str> = self.0.iter().map(|(_, title)| title.as_str()).collect();
+        path.join(" > ")
+    }
+}
+
+// Each ATX heading (`#` to `######`) starts a section named by the path of headings above it;
+// headings inside fenced code blocks are ignored
+fn load_markdown(path: &Path) -> Result<LoadedText> {
+    let text = read_text(path)?;
+    let mut sections = Vec::new();
+    let mut outline = Outline::default();
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+
+        if let Some(marker) = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| trimmed.starts_with(marker))
+        {
+            fence = match fence {
+                Some(open) if open == marker => None,
+                None => Some(marker),
+                other => other,
+            };
+        } else if fence.is_none() {
+            if let Some((level, title)) = parse_heading(trimmed) {
+                sections.push((offset, outline.enter(level, title)));
+            }
+        }
+
+        offset += line.len();
+    }
+
+    Ok(LoadedText {
+        text,
+        pages: Vec::new(),
+        sections,
+    })
+}
+
+// Level and title of an ATX heading line
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None; // "#hashtag" is not a heading
+    }
+
+    // Optional closing hashes: "## Title ##"
+    let title = rest.trim().trim_end_matches('#').trim_end();
+    Some((level, title))
+}
+
+// Elements whose content is never part of the page body
+const HTML_SKIPPED: &[&str] = &[
+    "script", "style", "noscript", "template", "nav", "aside", "form", "button", "iframe", "svg",
+    "canvas", "head",
+];
+
+// Elements that end the current line of text
+const HTML_LINES: &[&str] = &["br", "li", "tr", "dt", "dd"];
+
+// Elements that stand apart from the text around them as paragraphs
+const HTML_BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "blockquote",
+    "pre",
+    "ul",
+    "ol",
+    "dl",
+    "table",
+    "figure",
+    "figcaption",
+    "hr",
+    "details",
+    "summary",
+];
+
+// Text of the page's main content with boilerplate (navigation, scripts, styles, page header and
+// footer) dropped; each heading `<h1>` to `<h6>` starts a section like Markdown headings do
+fn load_html(path: &Path) -> Result<LoadedText> {
+    let html = Html::parse_document(&read_text(path)?);
+
+    // Prefer the element marked as the main content, falling back to the whole body
+    let root = ["main", "[role=main]", "article", "body"]
+        .into_iter()
+        .find_map(|selector| html.select(&Selector::parse(selector).unwrap()).next())
+        .unwrap_or_else(|| html.root_element());
+
+    let mut text = HtmlText::default();
+    text.element(root);
+    text.end_block();
+    Ok(text.loaded)
+}
+
+// Plain text being built from HTML, with whitespace collapsed as a browser would
+#[derive(Default)]
+struct HtmlText {
+    loaded: LoadedText,
+    outline: Outline,
+    // Whitespace was seen since the last word
+    space: bool,
+}
+
+impl HtmlText {
+    fn element(&mut self, element: ElementRef) {
+        let name = element.value().name();
+        let hidden = element.value().attr("hidden").is_some()
+            || element.value().attr("aria-hidden") == Some("true")
+            || matches!(
+                element.value().attr("role"),
+                Some("navigation" | "banner" | "contentinfo" | "search")
+            );
+        if HTML_SKIPPED.contains(&name) || hidden || is_page_chrome(element) {
+            return;
+        }
+
+        if let Some(level) = heading_level(name) {
+            let title = collapse_whitespace(&element.text().collect::<String>());
+            if !title.is_empty() {
+                self.end_block();
+                let path = self.outline.enter(level, &title);
+                self.loaded.sections.push((self.loaded.text.len(), path));
+                self.loaded.text.push_str(&title);
+                self.end_block();
+            }
+            return;
+        }
+
+        if name == "pre" {
+            // Preformatted text keeps its whitespace
+            self.end_block();
+            self.loaded.text.extend(element.text());
+            self.end_block();
+            return;
+        }
+
+        let block = HTML_BLOCKS.contains(&name);
+        if block {
+            self.end_block();
+        }
+
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.words(text),
+                Node::Element(_) => self.element(ElementRef::wrap(child).unwrap()),
+                _ => {}
+            }
+        }
+
+        if block {
+            self.end_block();
+        } else if HTML_LINES.contains(&name) {
+            self.end_line();
+        } else if matches!(name, "td" | "th") {
+            self.space = true;
+        }
+    }
+
+    fn words(&mut self, text: &str) {
+        if text.starts_with(char::is_whitespace) {
+            self.space = true;
+        }
+        for word in text.split_whitespace() {
+            let at_line_start = self.loaded.text.is_empty() || self.loaded.text.ends_with('\n');
+            if self.space && !at_line_start {
+                self.loaded.text.push(' ');
+            }
+            self.loaded.text.push_str(word);
+            self.space = true;
+        }
+        self.space = text.ends_with(char::is_whitespace);
+    }
+
+    fn end_line(&mut self) {
+        self.space = false;
+        if !self.loaded.text.is_empty() && !self.loaded.text.ends_with('\n') {
+            self.loaded.text.push('\n');
+        }
+    }
+
+    // Leaves a blank line after the text so far, which the paragraph-aware chunkers split on
+    fn end_block(&mut self) {
+        self.end_line();
+        if !self.loaded.text.is_empty() && !self.loaded.text.ends_with("\n\n") {
+            self.loaded.text.push('\n');
+        }
+    }
+}
+
+// The site-wide header or footer, as opposed to the header of an article or section
+fn is_page_chrome(element: ElementRef) -> bool {
+    matches!(element.value().name(), "header" | "footer")
+        && !element.ancestors().any(|ancestor| {
+            matches!(
+                ancestor.value().as_element().map(|parent| parent.name()),
+                Some("article" | "main" | "section")
+            )
+        })
+}
+
+fn heading_level(name: &str) -> Option<usize> {
+    match name {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::{ChunkUnit, FixedWindowChunker, Window};
+    use tempfile::TempDir;
+
+    // Loads `contents` as a file named `name`, one chunk per section
+    fn load(name: &str, contents: &str) -> Vec<Document> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        let source = SourceFile {
+            path,
+            id: name.to_string(),
+        };
+        let chunker = FixedWindowChunker(Window {
+            size: 1000,
+            overlap: 0,
+            unit: ChunkUnit::Words,
+        });
+        load_documents(&source, &chunker).unwrap()
+    }
+
+    // Two pages of three words each, as `load_pdf` lays them out
+    fn two_pages() -> LoadedText {
+        let text = "Page one text.\n\nPage two text.\n\n".to_string();
+        let second = text.find("Page two").unwrap();
+        LoadedText {
+            text,
+            pages: vec![(0, 1), (second, 2)],
+            sections: Vec::new(),
+        }
+    }
+
+    // Chunks of `size` words overlapping by `overlap`, with their page ranges
+    fn pages(size: usize, overlap: usize) -> Vec<(String, Option<usize>, Option<usize>)> {
+        let chunker = FixedWindowChunker(Window {
+            size,
+            overlap,
+            unit: ChunkUnit::Words,
+        });
+        chunk_text("manual.pdf", &two_pages(), &chunker)
+            .into_iter()
+            .map(|document| (document.content, document.page_start, document.page_end))
+            .collect()
+    }
+
+    fn page_range(
+        content: &str,
+        start: usize,
+        end: usize,
+    ) -> (String, Option<usize>, Option<usize>) {
+        (content.to_string(), Some(start), Some(end))
+    }
+
+    #[test]
+    fn chunks_within_a_page_have_that_page() {
+        assert_eq!(
+            pages(3, 0),
+            [
+                page_range("Page one text.", 1, 1),
+                page_range("Page two text.", 2, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_across_a_page_break_span_both_pages() {
+        assert_eq!(
+            pages(6, 0),
+            [page_range("Page one text. Page two text.", 1, 2)]
+        );
+    }
+
+    #[test]
+    fn chunks_starting_in_overlap_start_on_the_earlier_page() {
+        // The second chunk repeats the end of page one before going on to page two
+        assert_eq!(
+            pages(4, 2),
+            [
+                page_range("Page one text. Page", 1, 2),
+                page_range("text. Page two text.", 1, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn text_without_pages_has_no_page_range() {
+        let loaded = LoadedText {
+            text: "Just some notes.".to_string(),
+            ..LoadedText::default()
+        };
+        let chunker = FixedWindowChunker(Window {
+            size: 100,
+            overlap: 0,
+            unit: ChunkUnit::Words,
+        });
+        let documents = chunk_text("notes.txt", &loaded, &chunker);
+        assert_eq!(
+            (documents[0].page_start, documents[0].page_end),
+            (None, None)
+        );
+    }
+
+    fn html(contents: &str) -> LoadedText {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("page.html");
+        std::fs::write(&path, contents).unwrap();
+        load_html(&path).unwrap()
+    }
+
+    fn sections(documents: &[Document]) -> Vec<Option<&str>> {
+        documents
+            .iter()
+            .map(|document| document.section.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn files_without_text_have_no_chunks() {
+        assert!(load("empty.md", "").is_empty());
+        assert!(load("blank.txt", "  \n\n\t\n").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_pdf_paths_are_an_error() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(OsStr::from_bytes(b"r\xe9sum\xe9.pdf"));
+        std::fs::write(&path, "").unwrap();
+
+        let message = format!("{:#}", load_pdf(&path).unwrap_err());
+        assert!(message.contains("not valid UTF-8"), "{message}");
+    }
+
+    #[test]
+    fn markdown_headings_start_sections() {
+        let documents = load(
+            "guide.md",
+            "Intro text.\n\n# Setup\n\nInstall it.\n\n## Installing ##\n\nRun the installer.\n\n\
+             #hashtag stays in the text\n\n# Usage\nUse it.\n",
+        );
+
+        assert_eq!(
+            sections(&documents),
+            [
+                None,
+                Some("Setup"),
+                Some("Setup > Installing"),
+                Some("Usage")
+            ]
+        );
+        assert_eq!(documents[0].content, "Intro text.");
+        assert_eq!(
+            documents[2].content,
+            "## Installing ## Run the installer. #hashtag stays in the text"
+        );
+        let ids: Vec<&str> = documents
+            .iter()
+            .map(|document| document.id.as_str())
+            .collect();
+        assert_eq!(
+            ids,
+            ["guide.md#0", "guide.md#1", "guide.md#2", "guide.md#3"]
+        );
+    }
+
+    #[test]
+    fn headings_in_code_fences_are_code() {
+        let documents = load(
+            "fences.md",
+            "# Shell\n\n```sh\n# not a heading\n```\n\n~~~\n```\n# still code\n~~~\n\n## Next\nText.\n",
+        );
+
+        assert_eq!(sections(&documents), [Some("Shell"), Some("Shell > Next")]);
+        assert!(documents[0].content.contains("# not a heading"));
+        assert!(documents[0].content.contains("# still code"));
+    }
+
+    #[test]
+    fn html_boilerplate_is_dropped() {
+        let loaded = html(
+            r#"<html><head><title>Site</title><style>p { color: red }</style></head><body>
+              <header><a href="/">Home</a> <a href="/blog">Blog</a></header>
+              <nav><ul><li>Docs</li><li>About</li></ul></nav>
+              <div role="banner">Sale now on</div>
+              <section>
+                <header>Posted in Baking</header>
+                <p>Let the dough
+                   rise for <b>four</b> to six hours.</p>
+                <script>track("view");</script>
+                <aside>Related posts</aside>
+                <p hidden>Hidden text</p>
+              </section>
+              <footer>Copyright 2024</footer>
+            </body></html>"#,
+        );
+
+        // The section's own header stays; the page's header, footer and navigation don't
+        assert_eq!(
+            loaded.text,
+            "Posted in Baking\n\nLet the dough rise for four to six hours.\n\n"
+        );
+    }
+
+    #[test]
+    fn html_headings_start_sections() {
+        let loaded = html(
+            "<body><nav>Menu</nav><article><header><h1>Proofing  dough</h1></header>\
+             <p>Four to six hours.</p><h2>In the fridge</h2><p>Overnight.<br>Cover it.</p>\
+             <h2>Shaping</h2><p>Fold it.</p></article></body>",
+        );
+
+        assert_eq!(
+            loaded.text,
+            "Proofing dough\n\nFour to six hours.\n\nIn the fridge\n\nOvernight.\nCover it.\n\n\
+             Shaping\n\nFold it.\n\n"
+        );
+        let headings: Vec<&str> = loaded
+            .sections
+            .iter()
+            .map(|(_, path)| path.as_str())
+            .collect();
+        assert_eq!(
+            headings,
+            [
+                "Proofing dough",
+                "Proofing dough > In the fridge",
+                "Proofing dough > Shaping"
+            ]
+        );
+        let (start, _) = loaded.sections[1];
+        assert!(loaded.text[start..].starts_with("In the fridge"));
+    }
+
+    #[test]
+    fn html_preformatted_text_keeps_its_whitespace() {
+        let loaded = html(
+            "<main><p>Run:</p><pre>fn main() {\n    run();\n}</pre>\
+             <ul><li>One</li><li>Two</li></ul><table><tr><td>a</td><td>b</td></tr></table></main>",
+        );
+
+        assert_eq!(
+            loaded.text,
+            "Run:\n\nfn main() {\n    run();\n}\n\nOne\nTwo\n\na b\n\n"
+        );
+    }
+}
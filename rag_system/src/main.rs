@@ -1,121 +1,133 @@
 // Import error handling utilities from the anyhow crate
-use anyhow::{Context, Result};
-// Import various components from the rig crate (used for AI/ML operations)
-use rig::{
-    embeddings::EmbeddingsBuilder,        // For creating text embeddings
-    loaders::PdfFileLoader,              // For loading PDF files
-    providers::openai::{self, TEXT_EMBEDDING_ADA_002}, // For OpenAI client
-    vector_store::in_memory_store::InMemoryVectorStore, // For in-memory vector store
-    Embed,
-};
-use serde::{Deserialize, Serialize};        // For serialization and deserialization
-use std::path::PathBuf;                     // For handling file paths
+use anyhow::Result;
 use dotenv::dotenv;
+use clap::Parser;                           // For parsing command-line arguments
+use std::path::Path;
 
-#[derive(Embed, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]        // Define a struct for documents with embedding capabilities
-struct Document {
-    id: String,
-    #[embed]
-    content: String,
-}
-
-fn load_pdf(path: PathBuf) -> Result<Vec<String>> {
-    let mut chunks = Vec::new();
-    let mut current_chunk = String::new();
-    let chunk_size = 2000; // Approximately 2000 characters per chunk
-    
-    for entry in PdfFileLoader::with_glob(path.to_str().unwrap())?.read() {
-        let content = entry?;
-        
-        // Split content into words
-        let words: Vec<&str> = content.split_whitespace().collect();
-        
-        for word in words {
-            if current_chunk.len() + word.len() + 1 > chunk_size {
-                // If adding the next word would exceed chunk size,
-                // save current chunk and start a new one
-                if !current_chunk.is_empty() {
-                    chunks.push(current_chunk.trim().to_string());
-                    current_chunk.clear();
-                }
-            }
-            current_chunk.push_str(word);
-            current_chunk.push(' ');
-        }
-    }
-    
-    // last chunk
-    if !current_chunk.is_empty() {
-        chunks.push(current_chunk.trim().to_string());
-    }
+use rag_system::{chat, pipeline, server, store};
+use rag_system::config::Config;
+use rag_system::providers::{CompletionModel, EmbeddingModel};
+use rag_system::pipeline::Rag;              // The agent with its model and retrieval log
+use rag_system::store::StoredIndex;
 
-    if chunks.is_empty() {
-        anyhow::bail!("No content found in PDF file: {:?}", path);
-    }
-    
-    Ok(chunks)
-}
+mod cli;                                    // Subcommands and command-line options
+use cli::{Cli, Command, IndexArgs};
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     dotenv().ok(); // Load environment variables from .env file
-    // Initialize OpenAI client
-    let openai_client = openai::Client::from_env();
-    
-    // Load PDFs using Rig's built-in PDF loader
-    let documents_dir = std::env::current_dir()?.join("documents");
-    
-    let moores_law_chunks = load_pdf(documents_dir.join("01.pdf"))
-        .context("Failed to load 01.pdf")?;
-    let last_question_chunks = load_pdf(documents_dir.join("02.pdf"))
-        .context("Failed to load 02.pdf")?;
-
-    println!("Successfully loaded and chunked PDF documents");
-
-    // Create embedding model
-    let model = openai_client.embedding_model(TEXT_EMBEDDING_ADA_002);
-
-    // Create embeddings builder
-    let mut builder = EmbeddingsBuilder::new(model.clone());
-
-    // Add chunks from Moore's Law
-    for (i, chunk) in moores_law_chunks.into_iter().enumerate() {
-        builder = builder.document(Document {
-            id: format!("moores_law_{}", i),
-            content: chunk,
-        })?;
-    }
+    let cli = Cli::parse(); // Subcommand and options from the command line
 
-    // Add chunks from The Last Question
-    for (i, chunk) in last_question_chunks.into_iter().enumerate() {
-        builder = builder.document(Document {
-            id: format!("last_question_{}", i),
-            content: chunk,
-        })?;
-    }
+    // Settings from rag.toml, overridden by RAG_* environment variables and then by command-line flags
+    let config = Config::load(cli.config.as_deref(), &cli.config_overrides())?;
+
+    // Without a subcommand, start the interactive chat
+    match cli.command.unwrap_or(Command::Chat(IndexArgs::default())) {
+        Command::Ingest(args) => {
+            let model = EmbeddingModel::from_config(&config.embedding)?;
+            let stored = pipeline::build_index(model, &config, reindex(&args)).await?;
+            println!("{} chunks from {} files in {:?}", stored.entries.len(), stored.sources.len(), config.index.dir);
+        }
+        Command::Query { question, index } => {
+            let rag = build_agent(&config, &index).await?;
+
+            // Answer and sources go to stdout, so the output can be piped
+            if config.completion.stream {
+                let (_, answer) = chat::ask_streaming(&rag, &question, Vec::new(), chat::print_piece).await?;
+                print!("\n\n{}", answer.footer());
+            } else {
+                let (_, answer) = chat::ask(&rag, &question, Vec::new()).await?;
+                print!("{}\n\n{}", answer.text, answer.footer());
+            }
+        }
+        Command::Chat(args) => {
+            let model = EmbeddingModel::from_config(&config.embedding)?;
+            let completion_model = CompletionModel::from_config(&config.completion)?;
+            let reindex = reindex(&args);
+
+            // `/model NAME` switches to another chat model of the same provider
+            let session = chat::Session::start(config, model, completion_model, reindex).await?
+                .with_connect(CompletionModel::from_config);
 
-    // Build embeddings
-    let embeddings = builder.build().await?;
+            eprintln!("Starting CLI chatbot...");
 
-    println!("Successfully generated embeddings");
+            // Start interactive CLI
+            session.run().await?;
+        }
+        Command::Serve { addr, index } => {
+            let model = EmbeddingModel::from_config(&config.embedding)?;
+            let completion_model = CompletionModel::from_config(&config.completion)?;
+            let addr = addr.unwrap_or(config.server.addr);
 
-    // Create vector store and index
-    let vector_store = InMemoryVectorStore::from_documents(embeddings);
-    let index = vector_store.index(model);
+            let router = server::router(config, model, completion_model, reindex(&index)).await?;
+            server::serve(router, addr).await?;
+        }
+        Command::Stats => print_stats(&config.index.dir)?,
+    }
 
-    println!("Successfully created vector store and index");
+    Ok(())
+}
 
-    // Create RAG agent
-    let rag_agent = openai_client
-        .agent("gpt-4")
-        .preamble("You are a helpful assistant that answers questions based on the provided document context. When answering questions, try to synthesize information from multiple chunks if they're related.")
-        .dynamic_context(4, index) // Increased to 4 since we have chunks now
-        .build();
+// Updates the index and creates a RAG agent using the models of the configured providers
+async fn build_agent(config: &Config, args: &IndexArgs) -> Result<Rag<CompletionModel>> {
+    // Create the embedding and chat models of the configured providers (OpenAI or Ollama)
+    let model = EmbeddingModel::from_config(&config.embedding)?;
+    let completion_model = CompletionModel::from_config(&config.completion)?;
 
-    println!("Starting CLI chatbot...");
+    pipeline::build_agent(model, completion_model, config, reindex(args)).await
+}
 
-    // Start interactive CLI
-    rig::cli_chatbot::cli_chatbot(rag_agent).await?;
+// Pass --reindex (or set RAG_REINDEX=1) to ignore the saved index and embed every file again
+fn reindex(args: &IndexArgs) -> bool {
+    args.reindex || std::env::var("RAG_REINDEX").is_ok_and(|value| value == "1")
+}
+
+// Prints what the saved index holds, without touching the documents or the embedding API
+fn print_stats(index_dir: &Path) -> Result<()> {
+    let Some(stored) = StoredIndex::load(index_dir)? else {
+        anyhow::bail!("No index found in {:?}; run the `ingest` command first", index_dir);
+    };
+
+    let size = std::fs::metadata(index_dir.join(store::INDEX_FILE))?.len();
+    let outdated = if stored.version == store::FORMAT_VERSION { "" } else { " (outdated, run `ingest` to rebuild)" };
+
+    println!("Index:           {} ({})", index_dir.join(store::INDEX_FILE).display(), human_size(size));
+    println!("Format version:  {}{}", stored.version, outdated);
+    println!("Embedding model: {}", stored.embedding_model);
+    println!("Chunking:        {}", stored.chunking);
+    println!("Files:           {}", stored.sources.len());
+    println!("Chunks:          {}", stored.entries.len());
+
+    // Chunk count per file
+    for source in &stored.sources {
+        println!("  {:>6}  {}", source.chunks.len(), source.id);
+    }
 
     Ok(())
 }
+
+// "532 B", "14.2 KB", "3.1 MB"
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        0..=999 => format!("{bytes} B"),
+        // Up to the last size that doesn't round to "1000.0 KB"
+        1_000..=999_949 => format!("{:.1} KB", bytes as f64 / 1e3),
+        _ => format!("{:.1} MB", bytes as f64 / 1e6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_switch_unit_at_each_thousand() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(999), "999 B");
+        assert_eq!(human_size(1_000), "1.0 KB");
+        assert_eq!(human_size(14_249), "14.2 KB");
+        assert_eq!(human_size(999_949), "999.9 KB");
+        assert_eq!(human_size(999_950), "1.0 MB");
+        assert_eq!(human_size(3_100_000), "3.1 MB");
+    }
+}
@@ -0,0 +1,141 @@
+// Conversation history for follow-up questions: what the model is shown of it, and the
+// standalone search query retrieval uses instead of a follow-up that only makes sense in context
+use anyhow::{Context, Result};
+use rig::completion::{CompletionModel, Message, ModelChoice};
+
+use crate::tokens;
+
+// History budget used when the config doesn't set one
+pub const DEFAULT_HISTORY_TOKENS: usize = 2000;
+
+const CONDENSE_INSTRUCTIONS: &str = "Rewrite the user's follow-up question as a standalone question that can be understood without the conversation, for searching documents. Replace pronouns and references like \"that\" with what they refer to. If the question already stands on its own, repeat it unchanged. Reply with the question only.";
+
+/// Keeps follow-up questions tied to the conversation they belong to
+#[derive(Clone)]
+pub struct Memory<M> {
+    // Asked to condense follow-ups; the bare chat model, so no grounding applies
+    model: M,
+    condense: bool,
+    // Maximum tokens of history sent with a question
+    history_tokens: usize,
+}
+
+impl<M: CompletionModel> Memory<M> {
+    pub fn new(model: M, condense: bool, history_tokens: usize) -> Self {
+        Self {
+            model,
+            condense,
+            history_tokens,
+        }
+    }
+
+    /// The most recent part of `history` that fits the token budget
+    pub fn trim(&self, history: Vec<Message>) -> Vec<Message> {
+        trim_history(history, self.history_tokens)
+    }
+
+    /// What to search the documents for to answer `question`: `question` itself when there's no
+    /// history or condensing is off, otherwise the question rewritten to stand on its own
+    pub async fn search_query(&self, question: &str, history: &[Message]) -> Result<String> {
+        if !self.condense || history.is_empty() {
+            return Ok(question.to_string());
+        }
+
+        let mut prompt = String::from("Conversation:\n");
+        for message in history {
+            let speaker = match message.role.as_str() {
+                "user" => "User",
+                "assistant" => "Assistant",
+                _ => continue,
+            };
+            prompt.push_str(&format!("{speaker}: {}\n", message.content));
+        }
+        prompt.push_str(&format!("\nFollow-up question: {question}"));
+
+        let response = self
+            .model
+            .completion_request(&prompt)
+            .preamble(CONDENSE_INSTRUCTIONS.to_string())
+            .temperature(0.0)
+            .send()
+            .await
+            .context("Condensing the follow-up question failed")?;
+        let ModelChoice::Message(query) = response.choice else {
+            anyhow::bail!("The model called a tool instead of condensing the question");
+        };
+
+        // An empty rewrite is no use for searching
+        let query = query.trim();
+        Ok(if query.is_empty() { question } else { query }.to_string())
+    }
+}
+
+/// Adds a question and the model's answer to `history`. The raw answer is kept rather than the
+/// numbered one, so the model sees the ids it cited.
+pub fn record_turn(history: &mut Vec<Message>, question: &str, response: String) {
+    history.push(Message {
+        role: "user".into(),
+        content: question.into(),
+    });
+    history.push(Message {
+        role: "assistant".into(),
+        content: response,
+    });
+}
+
+/// Drops the oldest messages until the rest fit in `budget` tokens, and then any answers left
+/// at the start without their question
+pub fn trim_history(history: Vec<Message>, budget: usize) -> Vec<Message> {
+    let mut used = 0;
+    let mut start = history.len();
+    for (i, message) in history.iter().enumerate().rev() {
+        let cost = tokens::count_tokens(&message.content);
+        if used + cost > budget {
+            break;
+        }
+        used += cost;
+        start = i;
+    }
+    // An answer without its question would confuse the model
+    while history
+        .get(start)
+        .is_some_and(|message| message.role == "assistant")
+    {
+        start += 1;
+    }
+    history.into_iter().skip(start).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.into(),
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn oldest_turns_are_dropped_first() {
+        let history = vec![
+            message("user", "How long does sourdough rise?"),
+            message("assistant", "Four to six hours at room temperature."),
+            message("user", "Can it rise in the fridge?"),
+            message("assistant", "Yes, overnight."),
+        ];
+        let budget = tokens::count_tokens("Yes, overnight.")
+            + tokens::count_tokens("Can it rise in the fridge?");
+
+        let contents: Vec<String> = trim_history(history.clone(), budget)
+            .into_iter()
+            .map(|message| message.content)
+            .collect();
+        assert_eq!(contents, ["Can it rise in the fridge?", "Yes, overnight."]);
+
+        // Never starts with an answer whose question was dropped
+        let trimmed = trim_history(history, tokens::count_tokens("Yes, overnight."));
+        assert!(trimmed.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+// Maximal marginal relevance: picks chunks that are relevant to the question but unlike the chunks
+// already picked, so overlapping near-duplicates don't fill the agent's context
+use std::collections::HashMap;
+
+use crate::search::cosine_similarity;
+
+// Balance used when the config doesn't set one
+pub const DEFAULT_LAMBDA: f64 = 0.5;
+
+type Results = Vec<(f64, String, serde_json::Value)>;
+
+/// Selects results by maximal marginal relevance, using the chunks' stored embeddings to tell how
+/// similar they are to each other
+pub struct Mmr {
+    // 1 ranks by relevance alone, 0 by diversity alone
+    lambda: f64,
+    embeddings: HashMap<String, Vec<f64>>,
+}
+
+impl Mmr {
+    pub fn new(lambda: f64, embeddings: impl IntoIterator<Item = (String, Vec<f64>)>) -> Self {
+        Self {
+            lambda,
+            embeddings: embeddings.into_iter().collect(),
+        }
+    }
+
+    /// Picks `n` of `candidates` (best first) one at a time, each maximizing
+    /// `lambda * relevance - (1 - lambda) * highest similarity to a chunk already picked`.
+    /// Relevance is the candidate's score rescaled to 0..1 over the candidates, so it works with
+    /// any kind of score; the picked results keep their original scores.
+    pub fn select(&self, candidates: Results, n: usize) -> Results {
+        let min = candidates.iter().map(|r| r.0).fold(f64::INFINITY, f64::min);
+        let max = candidates
+            .iter()
+            .map(|r| r.0)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let relevance = |score: f64| {
+            if max > min {
+                (score - min) / (max - min)
+            } else {
+                1.0
+            }
+        };
+
+        let mut remaining = candidates;
+        let mut selected: Results = Vec::with_capacity(n.min(remaining.len()));
+        while selected.len() < n && !remaining.is_empty() {
+            let mut best = 0;
+            let mut best_value = f64::NEG_INFINITY;
+            for (i, (score, id, _)) in remaining.iter().enumerate() {
+                let redundancy = selected
+                    .iter()
+                    .map(|(_, other, _)| self.similarity(id, other))
+                    .fold(0.0, f64::max);
+                let value = self.lambda * relevance(*score) - (1.0 - self.lambda) * redundancy;
+                // Strictly greater, so ties go to the better-ranked candidate
+                if value > best_value {
+                    best = i;
+                    best_value = value;
+                }
+            }
+            selected.push(remaining.remove(best));
+        }
+        selected
+    }
+
+    // Cosine similarity of two chunks' embeddings; 0 if either is unknown
+    fn similarity(&self, a: &str, b: &str) -> f64 {
+        match (self.embeddings.get(a), self.embeddings.get(b)) {
+            (Some(a), Some(b)) => cosine_similarity(a, b),
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn candidates(ranked: &[(&str, f64)]) -> Results {
+        ranked
+            .iter()
+            .map(|(id, score)| (*score, id.to_string(), json!({ "id": id })))
+            .collect()
+    }
+
+    fn ids(results: &Results) -> Vec<&str> {
+        results.iter().map(|(_, id, _)| id.as_str()).collect()
+    }
+
+    // "a" and "a2" are near-duplicates, "b" points elsewhere
+    fn mmr(lambda: f64) -> Mmr {
+        Mmr::new(
+            lambda,
+            [
+                ("a".to_string(), vec![1.0, 0.0]),
+                ("a2".to_string(), vec![0.99, 0.1]),
+                ("b".to_string(), vec![0.0, 1.0]),
+            ],
+        )
+    }
+
+    #[test]
+    fn near_duplicates_give_way_to_distinct_chunks() {
+        let ranked = candidates(&[("a", 0.9), ("a2", 0.89), ("b", 0.8)]);
+        let selected = mmr(0.5).select(ranked, 2);
+
+        assert_eq!(ids(&selected), ["a", "b"]);
+        // Original scores are kept
+        assert_eq!(selected[1].0, 0.8);
+    }
+
+    #[test]
+    fn lambda_one_keeps_the_ranking() {
+        let ranked = candidates(&[("a", 0.9), ("a2", 0.89), ("b", 0.8)]);
+        assert_eq!(ids(&mmr(1.0).select(ranked, 3)), ["a", "a2", "b"]);
+    }
+
+    #[test]
+    fn selects_at_most_the_candidates() {
+        let ranked = candidates(&[("a", 0.9)]);
+        assert_eq!(ids(&mmr(0.5).select(ranked, 4)), ["a"]);
+    }
+}
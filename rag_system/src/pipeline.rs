@@ -0,0 +1,186 @@
+// Index building and agent construction, generic over the models so tests can swap in fakes
+use anyhow::Result;
+use rig::agent::{Agent, AgentBuilder};
+use rig::completion::{self, Completion, CompletionModel, CompletionRequestBuilder, Message};
+use rig::embeddings::EmbeddingModel;
+use rig::vector_store::VectorStoreIndexDyn;
+use std::collections::HashMap;
+
+use crate::citations;
+use crate::config::Config;
+use crate::grounding::Grounded;
+use crate::indexer;
+use crate::memory::Memory;
+use crate::mmr::Mmr;
+use crate::rerank::{KeywordReranker, LlmReranker, RerankMode};
+use crate::retrieval::Retriever;
+use crate::search::{SearchIndex, VectorIndex};
+use crate::store::StoredIndex;
+use crate::Document;
+
+/// The RAG agent, its chat model for streaming answers to the requests the agent builds, the
+/// index chunks are retrieved from for each question, and the conversation handling for
+/// follow-up questions
+pub struct Rag<C: CompletionModel> {
+    pub agent: Agent<Grounded<C>>,
+    pub model: Grounded<C>,
+    pub index: Box<dyn VectorStoreIndexDyn>,
+    pub top_k: usize,
+    pub memory: Memory<C>,
+}
+
+impl<C: CompletionModel> Rag<C> {
+    /// The agent's request answering `question`, along with the chunks retrieved for it by
+    /// searching for `query`, best match first. The chunks are attached the way the agent's
+    /// dynamic context would attach them. The index works as `.dynamic_context` too, but rig
+    /// then searches for the prompt itself and keeps the results to itself, while retrieving
+    /// here lets follow-ups search for their condensed question and every caller get back
+    /// exactly what its request was given.
+    pub async fn request(
+        &self,
+        question: &str,
+        query: &str,
+        history: Vec<Message>,
+    ) -> Result<(CompletionRequestBuilder<Grounded<C>>, Vec<Document>)> {
+        let mut documents = Vec::new();
+        let mut retrieved = Vec::new();
+        for (_, id, document) in self.index.top_n(query, self.top_k).await? {
+            documents.push(completion::Document {
+                id,
+                text: serde_json::to_string_pretty(&document)?,
+                additional_props: HashMap::new(),
+            });
+            retrieved.push(serde_json::from_value(document)?);
+        }
+
+        let request = self.agent.completion(question, history).await?;
+        Ok((request.documents(documents), retrieved))
+    }
+}
+
+/// Brings the saved index up to date with the documents directory and saves it if anything
+/// changed. With `reindex`, the saved index is ignored and every file is embedded again.
+/// Progress goes to stderr so `query` output stays clean.
+pub async fn build_index<M: EmbeddingModel>(
+    model: M,
+    config: &Config,
+    reindex: bool,
+) -> Result<StoredIndex> {
+    let chunk_options = config.chunk_options();
+    let embedding_model = &config.embedding.fingerprint();
+    let index_dir = &config.index.dir;
+
+    // Start from the saved index when it was built with the same embedding model and chunking
+    let chunking = chunk_options.to_string();
+    let previous = match StoredIndex::load(index_dir)? {
+        Some(index) if !reindex && index.is_compatible(embedding_model, &chunking) => index,
+        Some(_) if !reindex => {
+            eprintln!("Saved index is outdated, rebuilding it");
+            StoredIndex::empty(embedding_model, &chunking)
+        }
+        _ => StoredIndex::empty(embedding_model, &chunking),
+    };
+
+    // Only new or changed files are chunked and embedded
+    let discovery = config.discovery_options()?;
+    let (stored, update) = indexer::update_index(
+        model,
+        &chunk_options,
+        &discovery,
+        &config.documents.dir,
+        previous,
+    )
+    .await?;
+
+    eprintln!("Indexed {} files: {}", stored.sources.len(), update);
+
+    if !update.is_empty() {
+        stored.save(index_dir)?;
+        eprintln!("Saved index to {:?}", index_dir);
+    }
+
+    Ok(stored)
+}
+
+/// Updates the index and creates a RAG agent that retrieves from it
+pub async fn build_agent<E, C>(
+    embedding_model: E,
+    completion_model: C,
+    config: &Config,
+    reindex: bool,
+) -> Result<Rag<C>>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: CompletionModel + 'static,
+{
+    let stored = build_index(embedding_model.clone(), config, reindex).await?;
+    agent(stored, embedding_model, completion_model, config)
+}
+
+/// Creates a RAG agent retrieving from an index already brought up to date
+pub fn agent<E, C>(
+    stored: StoredIndex,
+    embedding_model: E,
+    completion_model: C,
+    config: &Config,
+) -> Result<Rag<C>>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: CompletionModel + 'static,
+{
+    let embeddings = stored.into_embeddings()?;
+    let documents = embeddings.iter().map(|(doc, _)| doc.clone()).collect();
+    // MMR compares the chunks with each other by their stored embeddings
+    let mmr = config.retrieval.mmr.then(|| {
+        let vectors = embeddings
+            .iter()
+            .map(|(doc, embedding)| (doc.id.clone(), embedding.first().vec));
+        Mmr::new(config.retrieval.mmr_lambda, vectors)
+    });
+
+    // Search the embeddings, a BM25 index over the same chunks, or both, as retrieval.mode says
+    let search = SearchIndex::new(
+        config.retrieval.mode,
+        VectorIndex::new(embedding_model, embeddings),
+        documents,
+        config.retrieval.rrf_k,
+    );
+    // Cap the retrieved context at retrieval.context_tokens tokens, if set
+    let mut index = Retriever::new(search, config.retrieval_options());
+    // Rerank retrieval.candidates chunks and keep the best, if retrieval.rerank says so
+    match config.retrieval.rerank {
+        RerankMode::None => {}
+        RerankMode::Keyword => index = index.with_reranker(KeywordReranker),
+        RerankMode::Llm => index = index.with_reranker(LlmReranker::new(completion_model.clone())),
+    }
+    // Then diversify what's left, if retrieval.mmr is set
+    if let Some(mmr) = mmr {
+        index = index.with_mmr(mmr);
+    }
+    eprintln!("Successfully created vector store and index");
+
+    // Follow-ups are condensed by the bare chat model and the history capped, as memory says
+    let memory = Memory::new(
+        completion_model.clone(),
+        config.memory.condense,
+        config.memory.history_tokens,
+    );
+
+    // Requests without documents get retrieval.no_context applied
+    let model = Grounded::new(completion_model, config.retrieval.no_context);
+    let rag_agent = AgentBuilder::new(model.clone())
+        .preamble(&format!(
+            "{} {}",
+            config.completion.preamble,
+            citations::CITATION_INSTRUCTIONS
+        ))
+        .build();
+
+    Ok(Rag {
+        agent: rag_agent,
+        model,
+        index: Box::new(index), // Chunks retrieved per question
+        top_k: config.retrieval.top_k,
+        memory,
+    })
+}
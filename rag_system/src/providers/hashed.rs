@@ -0,0 +1,193 @@
+// Deterministic offline embeddings: words, word pairs and character trigrams hashed into a fixed
+// number of dimensions. Much weaker than a trained model, but needs no network, key or download,
+// so ingestion and retrieval can run in tests and air-gapped environments.
+use rig::embeddings::{self, Embedding, EmbeddingError};
+
+// Model names are "hashed-ngrams-<dimensions>"
+pub const MODEL_PREFIX: &str = "hashed-ngrams-";
+
+pub const DEFAULT_MODEL: &str = "hashed-ngrams-512";
+
+// Feature kinds are hashed with different seeds so e.g. the word "the" and the trigram "the" differ
+const WORD: u8 = 1;
+const WORD_PAIR: u8 = 2;
+const TRIGRAM: u8 = 3;
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingModel {
+    ndims: usize,
+}
+
+impl EmbeddingModel {
+    pub fn new(ndims: usize) -> Self {
+        assert!(ndims > 0, "Embeddings need at least one dimension");
+        Self { ndims }
+    }
+
+    /// Parses a model name such as "hashed-ngrams-512"
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        let ndims = name
+            .strip_prefix(MODEL_PREFIX)
+            .and_then(|ndims| ndims.parse().ok())
+            .filter(|ndims| *ndims > 0)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Unknown hashed model {name:?}, expected e.g. \"{DEFAULT_MODEL}\"")
+            })?;
+        Ok(Self::new(ndims))
+    }
+
+    /// The unit-length embedding vector of `text`; the zero vector if it has no words
+    pub fn embed(&self, text: &str) -> Vec<f64> {
+        let mut vector = vec![0.0; self.ndims];
+        let text = text.to_lowercase();
+        let words: Vec<&str> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        for word in &words {
+            self.add(&mut vector, WORD, word.as_bytes(), 1.0);
+
+            // Trigrams of the padded word match inflections and typos: "index" ~ "indexing"
+            let padded: Vec<char> = format!(" {word} ").chars().collect();
+            for trigram in padded.windows(3) {
+                let trigram: String = trigram.iter().collect();
+                self.add(&mut vector, TRIGRAM, trigram.as_bytes(), 0.5);
+            }
+        }
+        for pair in words.windows(2) {
+            self.add(
+                &mut vector,
+                WORD_PAIR,
+                format!("{} {}", pair[0], pair[1]).as_bytes(),
+                1.0,
+            );
+        }
+
+        let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+
+    // Adds `weight` to the feature's dimension, with a hash-chosen sign so collisions tend to cancel
+    fn add(&self, vector: &mut [f64], kind: u8, feature: &[u8], weight: f64) {
+        let hash = fnv1a(kind, feature);
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % self.ndims as u64) as usize] += sign * weight;
+    }
+}
+
+impl Default for EmbeddingModel {
+    fn default() -> Self {
+        Self::from_name(DEFAULT_MODEL).unwrap()
+    }
+}
+
+// 64-bit FNV-1a; unlike std's hashers, its output is fixed forever, so saved indexes stay valid
+fn fnv1a(seed: u8, bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in std::iter::once(&seed).chain(bytes) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl embeddings::EmbeddingModel for EmbeddingModel {
+    const MAX_DOCUMENTS: usize = 1024;
+
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        Ok(texts
+            .into_iter()
+            .map(|text| Embedding {
+                vec: self.embed(&text),
+                document: text,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rig::embeddings::EmbeddingsBuilder;
+    use rig::vector_store::VectorStoreIndex;
+
+    use crate::search::{cosine_similarity, VectorIndex};
+    use crate::testing::document;
+
+    #[test]
+    fn embeddings_are_deterministic_and_unit_length() {
+        let model = EmbeddingModel::default();
+        let first = model.embed("The index is saved to disk.");
+        let second = EmbeddingModel::default().embed("The index is saved to disk.");
+
+        assert_eq!(first.len(), 512);
+        assert_eq!(first, second);
+        assert!((cosine_similarity(&first, &first) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_text_has_zero_vector() {
+        let vector = EmbeddingModel::new(16).embed(" -- ");
+        assert_eq!(vector, vec![0.0; 16]);
+    }
+
+    #[test]
+    fn case_and_punctuation_are_ignored() {
+        let model = EmbeddingModel::default();
+        assert_eq!(model.embed("Hello, World!"), model.embed("hello world"));
+    }
+
+    #[test]
+    fn related_texts_are_closer_than_unrelated_ones() {
+        let model = EmbeddingModel::default();
+        let query = model.embed("how is the index rebuilt");
+        let related = model.embed("The index is rebuilt when the chunk settings change.");
+        let unrelated = model.embed("Bananas are rich in potassium and grow in bunches.");
+
+        assert!(cosine_similarity(&query, &related) > cosine_similarity(&query, &unrelated));
+    }
+
+    #[test]
+    fn model_names_set_dimensions() {
+        assert_eq!(
+            EmbeddingModel::from_name("hashed-ngrams-64").unwrap().ndims,
+            64
+        );
+        assert!(EmbeddingModel::from_name("hashed-ngrams-0").is_err());
+        assert!(EmbeddingModel::from_name("text-embedding-ada-002").is_err());
+    }
+
+    #[tokio::test]
+    async fn retrieves_matching_chunk_through_vector_index() {
+        let model = EmbeddingModel::default();
+        let embeddings = EmbeddingsBuilder::new(model.clone())
+            .documents(vec![
+                document("notes.md#0", "Install the tool with cargo install."),
+                document("notes.md#1", "Chunks overlap by 200 characters by default."),
+                document("notes.md#2", "Answers cite the chunks they are based on."),
+            ])
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let index = VectorIndex::new(model, embeddings);
+
+        let results = index
+            .top_n_ids("how much do chunks overlap", 1)
+            .await
+            .unwrap();
+        assert_eq!(results[0].1, "notes.md#1");
+    }
+}
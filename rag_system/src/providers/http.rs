@@ -0,0 +1,143 @@
+// JSON-over-HTTP plumbing shared by the providers
+use anyhow::{Context, Result};
+use futures::{stream, Stream};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::Response;
+use serde::Deserialize;
+
+/// Where a provider's API lives and how to authenticate with it
+#[derive(Clone, Debug, Default)]
+pub struct Endpoint {
+    pub base_url: String,
+    // Sent as a bearer token
+    pub api_key: Option<String>,
+    // Sent as the `api-version` query parameter, as Azure-style gateways expect
+    pub api_version: Option<String>,
+    // Added to every request
+    pub headers: Vec<(String, String)>,
+}
+
+/// Posts JSON to the paths of one endpoint
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    base_url: String,
+    api_version: Option<String>,
+    headers: HeaderMap,
+    http: reqwest::Client,
+}
+
+impl HttpClient {
+    pub fn new(endpoint: &Endpoint) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = &endpoint.api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {api_key}"))
+                .context("The API key contains characters not allowed in a header")?;
+            headers.insert(AUTHORIZATION, value);
+        }
+        for (name, value) in &endpoint.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid header name {name:?}"))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for header {name}"))?;
+            headers.insert(name, value);
+        }
+
+        Ok(Self {
+            base_url: endpoint.base_url.trim_end_matches('/').to_string(),
+            api_version: endpoint.api_version.clone(),
+            headers,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Posts `body` to `path` and returns the response body, or the server's error message
+    pub async fn post(&self, path: &str, body: &serde_json::Value) -> Result<String, String> {
+        let response = self.send(path, body).await?;
+        response.text().await.map_err(|e| e.to_string())
+    }
+
+    /// Posts `body` to `path` and returns the response body line by line as it arrives,
+    /// for streamed answers
+    pub async fn post_lines(
+        &self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<impl Stream<Item = Result<String>> + Send, String> {
+        let response = self.send(path, body).await?;
+        Ok(stream::try_unfold(
+            (response, Vec::new()),
+            |(mut response, mut buffer)| async move {
+                loop {
+                    if let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=end).collect();
+                        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+                        return Ok(Some((line, (response, buffer))));
+                    }
+                    match response
+                        .chunk()
+                        .await
+                        .context("Reading the response failed")?
+                    {
+                        Some(chunk) => buffer.extend_from_slice(&chunk),
+                        None if buffer.is_empty() => return Ok(None),
+                        // The last line may lack a newline
+                        None => {
+                            let line = String::from_utf8_lossy(&buffer).trim_end().to_string();
+                            buffer.clear();
+                            return Ok(Some((line, (response, buffer))));
+                        }
+                    }
+                }
+            },
+        ))
+    }
+
+    // Sends the request, turning error statuses into the server's error message
+    async fn send(&self, path: &str, body: &serde_json::Value) -> Result<Response, String> {
+        let url = format!("{}/{}", self.base_url, path);
+
+        let mut request = self
+            .http
+            .post(&url)
+            .headers(self.headers.clone())
+            .json(body);
+        if let Some(api_version) = &self.api_version {
+            request = request.query(&[("api-version", api_version)]);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Request to {url} failed: {e}"))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.map_err(|e| e.to_string())?;
+            return Err(format!("{url} returned {status}: {}", error_message(text)));
+        }
+
+        Ok(response)
+    }
+}
+
+// Servers report failures as {"error": "..."} (Ollama) or {"error": {"message": "..."}} (OpenAI)
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ErrorBody {
+    Message(String),
+    Object { message: String },
+}
+
+fn error_message(body: String) -> String {
+    match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(ErrorResponse {
+            error: ErrorBody::Message(message) | ErrorBody::Object { message },
+        }) => message,
+        Err(_) => body,
+    }
+}
@@ -0,0 +1,118 @@
+// Scriptable stand-in for a chat model that records every request, so tests can check which
+// chunks the agent was given without calling a real provider
+use anyhow::Result;
+use futures::stream;
+use rig::completion::{self, CompletionError, CompletionRequest, Document, Message, ModelChoice};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::streaming::{StreamingCompletionModel, TokenStream};
+
+/// What the agent sent the model for one question
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub prompt: String,
+    pub preamble: Option<String>,
+    pub chat_history: Vec<Message>,
+    // The retrieved chunks `Rag::request` attached, best match first
+    pub documents: Vec<Document>,
+}
+
+impl RecordedRequest {
+    /// Ids of the chunks the agent was given
+    pub fn document_ids(&self) -> Vec<&str> {
+        self.documents
+            .iter()
+            .map(|document| document.id.as_str())
+            .collect()
+    }
+}
+
+type Responder = dyn Fn(&RecordedRequest) -> Result<String, String> + Send + Sync;
+
+#[derive(Clone)]
+pub struct CompletionModel {
+    respond: Arc<Responder>,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl CompletionModel {
+    /// Answers every request with whatever `respond` returns for it
+    pub fn new(respond: impl Fn(&RecordedRequest) -> String + Send + Sync + 'static) -> Self {
+        Self::from_responder(move |request| Ok(respond(request)))
+    }
+
+    /// Answers with `responses` in order, then fails every further request
+    pub fn with_responses(responses: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let responses: Mutex<VecDeque<String>> =
+            Mutex::new(responses.into_iter().map(Into::into).collect());
+        Self::from_responder(move |_| {
+            responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| "No canned responses left".to_string())
+        })
+    }
+
+    fn from_responder(
+        respond: impl Fn(&RecordedRequest) -> Result<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            respond: Arc::new(respond),
+            requests: Arc::default(),
+        }
+    }
+
+    /// Every request received so far, oldest first; clones share the same record
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn last_request(&self) -> Option<RecordedRequest> {
+        self.requests.lock().unwrap().last().cloned()
+    }
+
+    // Records the request and returns the response for it
+    fn answer(&self, request: CompletionRequest) -> Result<String, String> {
+        let request = RecordedRequest {
+            prompt: request.prompt,
+            preamble: request.preamble,
+            chat_history: request.chat_history,
+            documents: request.documents,
+        };
+        let response = (self.respond)(&request);
+        self.requests.lock().unwrap().push(request);
+        response
+    }
+}
+
+impl completion::CompletionModel for CompletionModel {
+    type Response = ();
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<()>, CompletionError> {
+        let response = self
+            .answer(request)
+            .map_err(CompletionError::ProviderError)?;
+
+        Ok(completion::CompletionResponse {
+            choice: ModelChoice::Message(response),
+            raw_response: (),
+        })
+    }
+}
+
+impl StreamingCompletionModel for CompletionModel {
+    // Streams the response word by word, so citation markers can arrive split across pieces
+    async fn stream(&self, request: CompletionRequest) -> Result<TokenStream> {
+        let response = self.answer(request).map_err(anyhow::Error::msg)?;
+        let pieces: Vec<Result<String>> = response
+            .split_inclusive(' ')
+            .map(|piece| Ok(piece.to_string()))
+            .collect();
+        Ok(Box::pin(stream::iter(pieces)))
+    }
+}
@@ -0,0 +1,219 @@
+// Embedding and chat models from the provider selected in the config
+use anyhow::{Context, Result};
+use rig::completion::{self, CompletionError, CompletionRequest, Message};
+use rig::embeddings::{self, Embedding, EmbeddingError};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::config::{CompletionConfig, EmbeddingConfig};
+use crate::streaming::{StreamingCompletionModel, TokenStream};
+
+pub mod hashed;
+mod http;
+pub mod mock;
+pub mod ollama;
+pub mod openai;
+
+pub use http::{Endpoint, HttpClient};
+
+/// Services that can embed text or answer questions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provider {
+    OpenAI,
+    Ollama,
+    // Built-in hashed n-gram embeddings, for tests and offline use; embeddings only
+    Hashed,
+}
+
+impl Provider {
+    /// Where requests go when the config doesn't set a base URL
+    pub fn default_base_url(&self) -> Option<&'static str> {
+        match self {
+            Provider::OpenAI => Some(openai::DEFAULT_BASE_URL),
+            Provider::Ollama => Some(ollama::DEFAULT_BASE_URL),
+            Provider::Hashed => None,
+        }
+    }
+
+    /// The environment variable the API key is read from when the config doesn't name one
+    pub fn default_api_key_env(&self) -> Option<&'static str> {
+        match self {
+            Provider::OpenAI => Some(openai::DEFAULT_API_KEY_ENV),
+            Provider::Ollama | Provider::Hashed => None,
+        }
+    }
+
+    /// The embedding model used when the config doesn't name one
+    pub fn default_embedding_model(&self) -> &'static str {
+        match self {
+            Provider::OpenAI => openai::TEXT_EMBEDDING_ADA_002,
+            Provider::Ollama => "nomic-embed-text",
+            Provider::Hashed => hashed::DEFAULT_MODEL,
+        }
+    }
+
+    /// The chat model used when the config doesn't name one
+    pub fn default_completion_model(&self) -> &'static str {
+        match self {
+            Provider::OpenAI => "gpt-4",
+            Provider::Ollama => "llama3.1",
+            Provider::Hashed => "",
+        }
+    }
+}
+
+impl FromStr for Provider {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "openai" => Ok(Provider::OpenAI),
+            "ollama" => Ok(Provider::Ollama),
+            "hashed" => Ok(Provider::Hashed),
+            _ => anyhow::bail!(
+                "Unknown provider {value:?}, expected \"openai\", \"ollama\" or \"hashed\""
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provider::OpenAI => write!(f, "openai"),
+            Provider::Ollama => write!(f, "ollama"),
+            Provider::Hashed => write!(f, "hashed"),
+        }
+    }
+}
+
+/// The embedding model of whichever provider is configured
+#[derive(Clone)]
+pub enum EmbeddingModel {
+    OpenAI(openai::EmbeddingModel),
+    Ollama(ollama::EmbeddingModel),
+    Hashed(hashed::EmbeddingModel),
+}
+
+impl EmbeddingModel {
+    pub fn from_config(config: &EmbeddingConfig) -> Result<Self> {
+        let http = || HttpClient::new(&config.endpoint()?).context("Invalid embedding settings");
+        Ok(match config.provider {
+            Provider::OpenAI => {
+                Self::OpenAI(openai::Client::new(http()?).embedding_model(config.model()))
+            }
+            Provider::Ollama => {
+                Self::Ollama(ollama::Client::new(http()?).embedding_model(config.model()))
+            }
+            Provider::Hashed => Self::Hashed(hashed::EmbeddingModel::from_name(config.model())?),
+        })
+    }
+}
+
+impl embeddings::EmbeddingModel for EmbeddingModel {
+    const MAX_DOCUMENTS: usize = 1024;
+
+    fn ndims(&self) -> usize {
+        match self {
+            Self::OpenAI(model) => model.ndims(),
+            Self::Ollama(model) => model.ndims(),
+            Self::Hashed(model) => model.ndims(),
+        }
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        match self {
+            Self::OpenAI(model) => model.embed_texts(texts).await,
+            Self::Ollama(model) => model.embed_texts(texts).await,
+            Self::Hashed(model) => model.embed_texts(texts).await,
+        }
+    }
+}
+
+/// The chat model of whichever provider is configured
+#[derive(Clone)]
+pub enum CompletionModel {
+    OpenAI(openai::CompletionModel),
+    Ollama(ollama::CompletionModel),
+}
+
+impl CompletionModel {
+    pub fn from_config(config: &CompletionConfig) -> Result<Self> {
+        let http = || HttpClient::new(&config.endpoint()?).context("Invalid completion settings");
+        Ok(match config.provider {
+            Provider::OpenAI => {
+                Self::OpenAI(openai::Client::new(http()?).completion_model(config.model()))
+            }
+            Provider::Ollama => {
+                Self::Ollama(ollama::Client::new(http()?).completion_model(config.model()))
+            }
+            Provider::Hashed => anyhow::bail!("The hashed provider only provides embeddings"),
+        })
+    }
+}
+
+impl completion::CompletionModel for CompletionModel {
+    // Raw responses differ per provider and nothing reads them
+    type Response = ();
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<()>, CompletionError> {
+        let choice = match self {
+            Self::OpenAI(model) => model.completion(request).await?.choice,
+            Self::Ollama(model) => model.completion(request).await?.choice,
+        };
+
+        Ok(completion::CompletionResponse {
+            choice,
+            raw_response: (),
+        })
+    }
+}
+
+impl StreamingCompletionModel for CompletionModel {
+    async fn stream(&self, request: CompletionRequest) -> Result<TokenStream> {
+        match self {
+            Self::OpenAI(model) => model.stream(request).await,
+            Self::Ollama(model) => model.stream(request).await,
+        }
+    }
+}
+
+/// The chat messages for `request`: the preamble as system message, the history, then the prompt
+/// with the retrieved documents attached the way rig's own providers attach them
+pub fn messages(request: &CompletionRequest) -> Vec<Message> {
+    let mut messages = Vec::new();
+
+    if let Some(preamble) = &request.preamble {
+        messages.push(Message {
+            role: "system".into(),
+            content: preamble.clone(),
+        });
+    }
+    messages.extend(request.chat_history.iter().cloned());
+
+    let prompt = if request.documents.is_empty() {
+        request.prompt.clone()
+    } else {
+        let attachments: String = request
+            .documents
+            .iter()
+            .map(|document| document.to_string())
+            .collect();
+        format!(
+            "<attachments>\n{attachments}</attachments>\n\n{}",
+            request.prompt
+        )
+    };
+    messages.push(Message {
+        role: "user".into(),
+        content: prompt,
+    });
+
+    messages
+}
@@ -0,0 +1,173 @@
+// Embedding and chat models served by Ollama's native API (`/api/embed` and `/api/chat`)
+use anyhow::Result;
+use futures::TryStreamExt;
+use rig::completion::{self, CompletionError, CompletionRequest, ModelChoice};
+use rig::embeddings::{self, Embedding, EmbeddingError};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::http::HttpClient;
+use crate::streaming::{StreamingCompletionModel, TokenStream};
+
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// Client for an Ollama server, or anything speaking the same API
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: HttpClient,
+}
+
+impl Client {
+    pub fn new(http: HttpClient) -> Self {
+        Self { http }
+    }
+
+    pub fn embedding_model(&self, model: &str) -> EmbeddingModel {
+        EmbeddingModel {
+            client: self.clone(),
+            model: model.to_string(),
+        }
+    }
+
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel {
+            client: self.clone(),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingModel {
+    client: Client,
+    pub model: String,
+}
+
+#[derive(Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f64>>,
+}
+
+impl embeddings::EmbeddingModel for EmbeddingModel {
+    const MAX_DOCUMENTS: usize = 1024;
+
+    // Depends on the model and isn't known until the first response; the in-memory store doesn't need it
+    fn ndims(&self) -> usize {
+        0
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let texts: Vec<String> = texts.into_iter().collect();
+
+        let body = self
+            .client
+            .http
+            .post("api/embed", &json!({ "model": self.model, "input": texts }))
+            .await
+            .map_err(EmbeddingError::ProviderError)?;
+        let response: EmbedResponse = serde_json::from_str(&body)?;
+
+        if response.embeddings.len() != texts.len() {
+            return Err(EmbeddingError::ResponseError(format!(
+                "Expected {} embeddings, got {}",
+                texts.len(),
+                response.embeddings.len()
+            )));
+        }
+
+        Ok(texts
+            .into_iter()
+            .zip(response.embeddings)
+            .map(|(document, vec)| Embedding { document, vec })
+            .collect())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompletionModel {
+    client: Client,
+    pub model: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatResponse {
+    pub message: completion::Message,
+}
+
+// One line of a streamed answer; errors can also arrive mid-stream
+#[derive(Deserialize)]
+struct ChatChunk {
+    message: Option<completion::Message>,
+    error: Option<String>,
+}
+
+impl CompletionModel {
+    fn body(&self, request: &CompletionRequest, stream: bool) -> serde_json::Value {
+        let mut options = serde_json::Map::new();
+        if let Some(temperature) = request.temperature {
+            options.insert("temperature".into(), json!(temperature));
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            options.insert("num_predict".into(), json!(max_tokens));
+        }
+
+        json!({
+            "model": self.model,
+            "messages": super::messages(request),
+            "options": options,
+            "stream": stream,
+        })
+    }
+}
+
+impl completion::CompletionModel for CompletionModel {
+    type Response = ChatResponse;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<ChatResponse>, CompletionError> {
+        let body = self.body(&request, false);
+        let body = self
+            .client
+            .http
+            .post("api/chat", &body)
+            .await
+            .map_err(CompletionError::ProviderError)?;
+        let response: ChatResponse = serde_json::from_str(&body)?;
+
+        Ok(completion::CompletionResponse {
+            choice: ModelChoice::Message(response.message.content.clone()),
+            raw_response: response,
+        })
+    }
+}
+
+impl StreamingCompletionModel for CompletionModel {
+    async fn stream(&self, request: CompletionRequest) -> Result<TokenStream> {
+        let lines = self
+            .client
+            .http
+            .post_lines("api/chat", &self.body(&request, true))
+            .await
+            .map_err(anyhow::Error::msg)?;
+
+        // One JSON object per line, the last with `"done": true`
+        Ok(Box::pin(lines.try_filter_map(|line| async move {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            let chunk: ChatChunk = serde_json::from_str(&line)?;
+            if let Some(error) = chunk.error {
+                anyhow::bail!("Ollama failed while answering: {error}");
+            }
+            Ok(chunk
+                .message
+                .map(|message| message.content)
+                .filter(|content| !content.is_empty()))
+        })))
+    }
+}
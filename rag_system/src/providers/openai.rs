@@ -0,0 +1,230 @@
+// Embedding and chat models behind any OpenAI-compatible API (OpenAI, vLLM, LiteLLM, Azure-style gateways)
+use anyhow::Result;
+use futures::TryStreamExt;
+use rig::completion::{self, CompletionError, CompletionRequest, ModelChoice};
+use rig::embeddings::{self, Embedding, EmbeddingError};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::http::HttpClient;
+use crate::streaming::{StreamingCompletionModel, TokenStream};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+// Environment variable the API key is read from unless the config names another
+pub const DEFAULT_API_KEY_ENV: &str = "OPENAI_API_KEY";
+
+pub const TEXT_EMBEDDING_ADA_002: &str = "text-embedding-ada-002";
+pub const TEXT_EMBEDDING_3_SMALL: &str = "text-embedding-3-small";
+pub const TEXT_EMBEDDING_3_LARGE: &str = "text-embedding-3-large";
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: HttpClient,
+}
+
+impl Client {
+    pub fn new(http: HttpClient) -> Self {
+        Self { http }
+    }
+
+    pub fn embedding_model(&self, model: &str) -> EmbeddingModel {
+        let ndims = match model {
+            TEXT_EMBEDDING_ADA_002 | TEXT_EMBEDDING_3_SMALL => 1536,
+            TEXT_EMBEDDING_3_LARGE => 3072,
+            _ => 0, // Unknown until the first response; the in-memory store doesn't need it
+        };
+
+        EmbeddingModel {
+            client: self.clone(),
+            model: model.to_string(),
+            ndims,
+        }
+    }
+
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel {
+            client: self.clone(),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingModel {
+    client: Client,
+    pub model: String,
+    ndims: usize,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f64>,
+    index: usize,
+}
+
+impl embeddings::EmbeddingModel for EmbeddingModel {
+    const MAX_DOCUMENTS: usize = 1024;
+
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let texts: Vec<String> = texts.into_iter().collect();
+
+        let body = self
+            .client
+            .http
+            .post(
+                "embeddings",
+                &json!({ "model": self.model, "input": texts }),
+            )
+            .await
+            .map_err(EmbeddingError::ProviderError)?;
+        let mut response: EmbeddingResponse = serde_json::from_str(&body)?;
+
+        if response.data.len() != texts.len() {
+            return Err(EmbeddingError::ResponseError(format!(
+                "Expected {} embeddings, got {}",
+                texts.len(),
+                response.data.len()
+            )));
+        }
+
+        // Each embedding says which input it belongs to
+        response.data.sort_by_key(|data| data.index);
+        Ok(texts
+            .into_iter()
+            .zip(response.data)
+            .map(|(document, data)| Embedding {
+                document,
+                vec: data.embedding,
+            })
+            .collect())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompletionModel {
+    client: Client,
+    pub model: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatResponse {
+    pub choices: Vec<ChatChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatChoice {
+    pub message: ChatMessage,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    // Null when the model called a tool instead of answering
+    pub content: Option<String>,
+}
+
+// One server-sent event of a streamed answer
+#[derive(Deserialize)]
+struct ChatChunk {
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChunkChoice {
+    delta: Delta,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+impl CompletionModel {
+    fn body(&self, request: &CompletionRequest) -> serde_json::Value {
+        let mut body = json!({
+            "model": self.model,
+            "messages": super::messages(request),
+        });
+        if let Some(temperature) = request.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        body
+    }
+}
+
+impl completion::CompletionModel for CompletionModel {
+    type Response = ChatResponse;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<ChatResponse>, CompletionError> {
+        let body = self.body(&request);
+        let body = self
+            .client
+            .http
+            .post("chat/completions", &body)
+            .await
+            .map_err(CompletionError::ProviderError)?;
+        let response: ChatResponse = serde_json::from_str(&body)?;
+
+        let content = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .ok_or_else(|| {
+                CompletionError::ResponseError("Response contained no message".into())
+            })?;
+
+        Ok(completion::CompletionResponse {
+            choice: ModelChoice::Message(content),
+            raw_response: response,
+        })
+    }
+}
+
+impl StreamingCompletionModel for CompletionModel {
+    async fn stream(&self, request: CompletionRequest) -> Result<TokenStream> {
+        let mut body = self.body(&request);
+        body["stream"] = json!(true);
+
+        let lines = self
+            .client
+            .http
+            .post_lines("chat/completions", &body)
+            .await
+            .map_err(anyhow::Error::msg)?;
+
+        // Server-sent events: `data: {chunk}` lines, ending with `data: [DONE]`
+        Ok(Box::pin(lines.try_filter_map(|line| async move {
+            let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                return Ok(None);
+            };
+            if data == "[DONE]" {
+                return Ok(None);
+            }
+            let chunk: ChatChunk = serde_json::from_str(data)?;
+            Ok(chunk
+                .choices
+                .into_iter()
+                .next()
+                .and_then(|choice| choice.delta.content)
+                .filter(|content| !content.is_empty()))
+        })))
+    }
+}
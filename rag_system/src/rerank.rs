@@ -0,0 +1,197 @@
+// Second-stage scoring of a wider candidate set, so the agent gets the chunks that best answer
+// the question rather than the ones whose embeddings happen to be closest
+use anyhow::{Context, Result};
+use rig::completion::{CompletionModel, ModelChoice};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+
+use crate::bm25::Bm25Index;
+use crate::Document;
+
+// Number of candidates retrieved for reranking or MMR when the config doesn't say
+pub const DEFAULT_CANDIDATES: usize = 30;
+
+// Candidates scored per chat request, keeping prompts well inside small context windows
+const LLM_BATCH_SIZE: usize = 10;
+
+// Longer chunks are cut to this many characters in reranking prompts
+const LLM_PASSAGE_CHARS: usize = 1500;
+
+const LLM_INSTRUCTIONS: &str = "You rate how well passages answer a search query. Score every passage from 0 (unrelated) to 10 (answers the query directly). Reply with one line per passage in the form `<passage number>: <score>` and nothing else.";
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Scores retrieved chunks against the query; implement it to plug in another model
+pub trait Reranker: Send + Sync {
+    /// Relevance of each of `documents` to `query` in the same order, higher meaning more relevant
+    fn scores<'a>(
+        &'a self,
+        query: &'a str,
+        documents: &'a [Document],
+    ) -> BoxFuture<'a, Result<Vec<f64>>>;
+}
+
+/// Which reranker to use, if any
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RerankMode {
+    None,
+    // BM25 within the candidates; local and free, a stand-in for a cross-encoder
+    Keyword,
+    // The chat model rates each candidate
+    Llm,
+}
+
+impl FromStr for RerankMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(RerankMode::None),
+            "keyword" => Ok(RerankMode::Keyword),
+            "llm" => Ok(RerankMode::Llm),
+            _ => anyhow::bail!(
+                "Unknown reranker {value:?}, expected \"none\", \"keyword\" or \"llm\""
+            ),
+        }
+    }
+}
+
+impl fmt::Display for RerankMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RerankMode::None => write!(f, "none"),
+            RerankMode::Keyword => write!(f, "keyword"),
+            RerankMode::Llm => write!(f, "llm"),
+        }
+    }
+}
+
+/// Ranks the candidates by BM25 computed over the candidates alone
+pub struct KeywordReranker;
+
+impl Reranker for KeywordReranker {
+    fn scores<'a>(
+        &'a self,
+        query: &'a str,
+        documents: &'a [Document],
+    ) -> BoxFuture<'a, Result<Vec<f64>>> {
+        Box::pin(async move {
+            let index = Bm25Index::new(documents.iter().cloned());
+            let scores: HashMap<&str, f64> = index
+                .search(query, documents.len())
+                .into_iter()
+                .map(|(score, document)| (document.id.as_str(), score))
+                .collect();
+
+            Ok(documents
+                .iter()
+                .map(|document| scores.get(document.id.as_str()).copied().unwrap_or(0.0))
+                .collect())
+        })
+    }
+}
+
+/// Asks a chat model to rate the candidates, in batches; unrated candidates score 0
+pub struct LlmReranker<M> {
+    model: M,
+}
+
+impl<M: CompletionModel> LlmReranker<M> {
+    pub fn new(model: M) -> Self {
+        Self { model }
+    }
+
+    async fn score_batch(&self, query: &str, documents: &[Document]) -> Result<Vec<f64>> {
+        let mut prompt = format!("Query: {query}\n");
+        for (number, document) in documents.iter().enumerate() {
+            let passage: String = document.content.chars().take(LLM_PASSAGE_CHARS).collect();
+            prompt.push_str(&format!("\nPassage {}:\n{}\n", number + 1, passage));
+        }
+
+        let response = self
+            .model
+            .completion_request(&prompt)
+            .preamble(LLM_INSTRUCTIONS.to_string())
+            .temperature(0.0)
+            .send()
+            .await
+            .context("Reranking request failed")?;
+        let ModelChoice::Message(reply) = response.choice else {
+            anyhow::bail!("The reranking model called a tool instead of answering");
+        };
+
+        Ok(parse_scores(&reply, documents.len()))
+    }
+}
+
+impl<M: CompletionModel> Reranker for LlmReranker<M> {
+    fn scores<'a>(
+        &'a self,
+        query: &'a str,
+        documents: &'a [Document],
+    ) -> BoxFuture<'a, Result<Vec<f64>>> {
+        Box::pin(async move {
+            let mut scores = Vec::with_capacity(documents.len());
+            for batch in documents.chunks(LLM_BATCH_SIZE) {
+                scores.extend(self.score_batch(query, batch).await?);
+            }
+            Ok(scores)
+        })
+    }
+}
+
+/// Reads `<passage number>: <score>` lines, tolerating "Passage 3: 7" and "3: 7/10";
+/// passages the reply doesn't rate get 0
+pub fn parse_scores(reply: &str, count: usize) -> Vec<f64> {
+    let mut scores = vec![0.0; count];
+    for line in reply.lines() {
+        let Some((number, score)) = line.split_once(':') else {
+            continue;
+        };
+        let number = number
+            .trim()
+            .trim_start_matches(|c: char| !c.is_ascii_digit())
+            .parse::<usize>();
+        let score = score
+            .trim()
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .and_then(|score| score.parse::<f64>().ok());
+
+        if let (Ok(number @ 1..), Some(score)) = (number, score) {
+            if let Some(slot) = scores.get_mut(number - 1) {
+                *slot = score;
+            }
+        }
+    }
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::document;
+
+    #[test]
+    fn scores_are_read_by_passage_number() {
+        let reply = "1: 3\nPassage 3: 9/10\n\n2: seven\n7: 10";
+        assert_eq!(parse_scores(reply, 4), [3.0, 0.0, 9.0, 0.0]);
+    }
+
+    #[tokio::test]
+    async fn keyword_reranker_prefers_matching_candidates() {
+        let documents = [
+            document("manual.txt#0", "Clean the housing with a dry cloth."),
+            document("manual.txt#1", "Replace the XR-200 filter every year."),
+        ];
+        let scores = KeywordReranker
+            .scores("how often to replace the XR-200 filter", &documents)
+            .await
+            .unwrap();
+
+        assert!(scores[1] > scores[0]);
+    }
+}
@@ -0,0 +1,180 @@
+// Post-processing of vector search results before they reach the agent
+use rig::vector_store::{VectorStoreError, VectorStoreIndex};
+use serde::Deserialize;
+
+use crate::mmr::Mmr;
+use crate::rerank::Reranker;
+use crate::tokens;
+use crate::Document;
+
+/// Limits applied to the chunks the agent receives with a question
+#[derive(Clone, Debug, Default)]
+pub struct RetrievalOptions {
+    // Maximum tokens across all retrieved chunks; `None` means no limit
+    pub context_tokens: Option<usize>,
+    // Results the index scores lower are dropped before any other stage; `None` keeps all.
+    // Only meaningful for scores on a fixed scale, which is why the config allows it in vector
+    // mode alone
+    pub min_score: Option<f64>,
+    // Results fetched from the index for the reranker or MMR to choose from, if either is used
+    pub candidates: usize,
+}
+
+type Results = Vec<(f64, String, serde_json::Value)>;
+
+/// A wrapper around the search index that `Rag::request` retrieves through, applying
+/// `RetrievalOptions` to the results of the wrapped index
+pub struct Retriever<I> {
+    index: I,
+    options: RetrievalOptions,
+    reranker: Option<Box<dyn Reranker>>,
+    mmr: Option<Mmr>,
+}
+
+impl<I: VectorStoreIndex> Retriever<I> {
+    pub fn new(index: I, options: RetrievalOptions) -> Self {
+        Self {
+            index,
+            options,
+            reranker: None,
+            mmr: None,
+        }
+    }
+
+    /// Reorders `options.candidates` results with `reranker` and keeps the best
+    pub fn with_reranker(mut self, reranker: impl Reranker + 'static) -> Self {
+        self.reranker = Some(Box::new(reranker));
+        self
+    }
+
+    /// Picks the results from `options.candidates` by maximal marginal relevance, after reranking
+    pub fn with_mmr(mut self, mmr: Mmr) -> Self {
+        self.mmr = Some(mmr);
+        self
+    }
+
+    async fn retrieve(&self, query: &str, n: usize) -> Result<Results, VectorStoreError> {
+        // Later stages choose from a wider set than they hand out
+        let depth = if self.reranker.is_some() || self.mmr.is_some() {
+            n.max(self.options.candidates)
+        } else {
+            n
+        };
+        // The search indexes hand back their results best first, which every later stage relies on
+        let mut results = self.index.top_n::<serde_json::Value>(query, depth).await?;
+
+        if let Some(min_score) = self.options.min_score {
+            results.retain(|(score, _, _)| *score >= min_score);
+        }
+
+        if let Some(reranker) = &self.reranker {
+            results = rerank(reranker.as_ref(), query, results).await?;
+        }
+        match &self.mmr {
+            Some(mmr) => results = mmr.select(results, n),
+            None => results.truncate(n),
+        }
+
+        if let Some(budget) = self.options.context_tokens {
+            // Keep results in rank order until the next one would go over the budget;
+            // the agent sees each document as pretty-printed JSON, so that's what is counted
+            let mut used = 0;
+            let mut kept = 0;
+            for (_, _, document) in &results {
+                let cost = tokens::count_tokens(&serde_json::to_string_pretty(document)?);
+                if used + cost > budget {
+                    break;
+                }
+                used += cost;
+                kept += 1;
+            }
+            results.truncate(kept);
+        }
+
+        Ok(results)
+    }
+}
+
+// `candidates` ordered by reranker score, which replaces the index's score
+async fn rerank(
+    reranker: &dyn Reranker,
+    query: &str,
+    candidates: Results,
+) -> Result<Results, VectorStoreError> {
+    let documents = candidates
+        .iter()
+        .map(|(_, _, document)| serde_json::from_value(document.clone()))
+        .collect::<Result<Vec<Document>, _>>()?;
+    let scores = reranker
+        .scores(query, &documents)
+        .await
+        .map_err(|e| VectorStoreError::DatastoreError(e.into()))?;
+
+    // Stable, so candidates the reranker can't tell apart keep the index's order
+    let mut results: Results = scores
+        .into_iter()
+        .zip(candidates)
+        .map(|(score, (_, id, document))| (score, id, document))
+        .collect();
+    results.sort_by(|a, b| b.0.total_cmp(&a.0));
+    Ok(results)
+}
+
+impl<I: VectorStoreIndex> VectorStoreIndex for Retriever<I> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        self.retrieve(query, n)
+            .await?
+            .into_iter()
+            .map(|(score, id, document)| Ok((score, id, serde_json::from_value(document)?)))
+            .collect()
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        Ok(self
+            .retrieve(query, n)
+            .await?
+            .into_iter()
+            .map(|(score, id, _)| (score, id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FixedResults;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn budget_keeps_the_best_matches() {
+        let results = [("best", 0.9), ("good", 0.5), ("weak", 0.1)]
+            .into_iter()
+            .map(|(id, score)| (score, id.to_string(), json!({ "id": id, "text": "x" })))
+            .collect();
+        let document_tokens = tokens::count_tokens(
+            &serde_json::to_string_pretty(&json!({ "id": "best", "text": "x" })).unwrap(),
+        );
+        let options = RetrievalOptions {
+            context_tokens: Some(2 * document_tokens),
+            ..RetrievalOptions::default()
+        };
+        let retriever = Retriever::new(FixedResults(results), options);
+
+        let ids: Vec<String> = retriever
+            .top_n_ids("question", 3)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+        assert_eq!(ids, ["best", "good"]);
+    }
+}
@@ -0,0 +1,417 @@
+// How chunks are found for a question: by embedding similarity, by BM25 keywords, or by both
+// with the two rankings merged through reciprocal rank fusion
+use anyhow::Result;
+use rig::embeddings::{Embedding, EmbeddingModel};
+use rig::vector_store::{VectorStoreError, VectorStoreIndex};
+use rig::OneOrMany;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::bm25::Bm25Index;
+use crate::Document;
+
+// The constant from the original RRF paper; larger values flatten the gap between ranks
+pub const DEFAULT_RRF_K: f64 = 60.0;
+
+// Each ranking contributes at least this many candidates to the fusion, so a chunk ranked
+// moderately by both can beat one ranked first by only one
+const MIN_FUSION_DEPTH: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchMode {
+    // Cosine similarity of embeddings only
+    Vector,
+    // BM25 over the chunk text only
+    Keyword,
+    // Both, fused by rank
+    Hybrid,
+}
+
+impl FromStr for SearchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "vector" => Ok(SearchMode::Vector),
+            "keyword" => Ok(SearchMode::Keyword),
+            "hybrid" => Ok(SearchMode::Hybrid),
+            _ => anyhow::bail!(
+                "Unknown retrieval mode {value:?}, expected \"vector\", \"keyword\" or \"hybrid\""
+            ),
+        }
+    }
+}
+
+impl fmt::Display for SearchMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchMode::Vector => write!(f, "vector"),
+            SearchMode::Keyword => write!(f, "keyword"),
+            SearchMode::Hybrid => write!(f, "hybrid"),
+        }
+    }
+}
+
+type Results = Vec<(f64, String, serde_json::Value)>;
+
+/// Embedding search scored by cosine similarity, best first. rig's in-memory index divides the
+/// dot product by the vectors' dimension counts instead of their lengths, so its scores shrink
+/// with the model's dimensions and mean nothing to `min_score`.
+pub struct VectorIndex<M> {
+    model: M,
+    // Each chunk with the embeddings of its text; a chunk scores as its best embedding
+    chunks: Vec<(Document, Vec<Vec<f64>>)>,
+}
+
+impl<M: EmbeddingModel> VectorIndex<M> {
+    pub fn new(
+        model: M,
+        chunks: impl IntoIterator<Item = (Document, OneOrMany<Embedding>)>,
+    ) -> Self {
+        let chunks = chunks
+            .into_iter()
+            .map(|(document, embeddings)| {
+                let vectors = embeddings
+                    .into_iter()
+                    .map(|embedding| embedding.vec)
+                    .collect();
+                (document, vectors)
+            })
+            .collect();
+        Self { model, chunks }
+    }
+
+    async fn search(&self, query: &str, n: usize) -> Result<Results, VectorStoreError> {
+        let query = self.model.embed_text(query).await?.vec;
+
+        let mut scored: Vec<(f64, &Document)> = self
+            .chunks
+            .iter()
+            .map(|(document, vectors)| {
+                let score = vectors
+                    .iter()
+                    .map(|vector| cosine_similarity(&query, vector))
+                    .fold(f64::NEG_INFINITY, f64::max);
+                (score, document)
+            })
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(n);
+
+        scored
+            .into_iter()
+            .map(|(score, document)| {
+                Ok((score, document.id.clone(), serde_json::to_value(document)?))
+            })
+            .collect()
+    }
+}
+
+impl<M: EmbeddingModel> VectorStoreIndex for VectorIndex<M> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        self.search(query, n)
+            .await?
+            .into_iter()
+            .map(|(score, id, document)| Ok((score, id, serde_json::from_value(document)?)))
+            .collect()
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        Ok(self
+            .search(query, n)
+            .await?
+            .into_iter()
+            .map(|(score, id, _)| (score, id))
+            .collect())
+    }
+}
+
+/// Cosine of the angle between two vectors, from -1 to 1; 0 if either has no length
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms =
+        a.iter().map(|x| x * x).sum::<f64>().sqrt() * b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norms > 0.0 {
+        dot / norms
+    } else {
+        0.0
+    }
+}
+
+/// Vector and BM25 search merged with reciprocal rank fusion: each chunk scores
+/// `1 / (rrf_k + rank)` summed over the rankings it appears in
+pub struct HybridIndex<V> {
+    vector: V,
+    keyword: Bm25Index,
+    rrf_k: f64,
+}
+
+impl<V: VectorStoreIndex> HybridIndex<V> {
+    pub fn new(vector: V, keyword: Bm25Index, rrf_k: f64) -> Self {
+        Self {
+            vector,
+            keyword,
+            rrf_k,
+        }
+    }
+
+    async fn search(&self, query: &str, n: usize) -> Result<Results, VectorStoreError> {
+        let depth = n.max(MIN_FUSION_DEPTH);
+        let vector = self.vector.top_n::<serde_json::Value>(query, depth).await?;
+        let keyword = self
+            .keyword
+            .top_n::<serde_json::Value>(query, depth)
+            .await?;
+
+        Ok(fuse([vector, keyword], self.rrf_k, n))
+    }
+}
+
+/// Merges rankings (each best first) into one of at most `n` results, best first. Scores are the
+/// fused RRF scores; chunks with equal scores keep the order in which they were first seen.
+pub fn fuse(rankings: impl IntoIterator<Item = Results>, rrf_k: f64, n: usize) -> Results {
+    let mut fused: Results = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for ranking in rankings {
+        for (rank, (_, id, document)) in ranking.into_iter().enumerate() {
+            let score = 1.0 / (rrf_k + rank as f64 + 1.0);
+            match positions.get(&id) {
+                Some(&position) => fused[position].0 += score,
+                None => {
+                    positions.insert(id.clone(), fused.len());
+                    fused.push((score, id, document));
+                }
+            }
+        }
+    }
+
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0));
+    fused.truncate(n);
+    fused
+}
+
+impl<V: VectorStoreIndex> VectorStoreIndex for HybridIndex<V> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        self.search(query, n)
+            .await?
+            .into_iter()
+            .map(|(score, id, document)| Ok((score, id, serde_json::from_value(document)?)))
+            .collect()
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        Ok(self
+            .search(query, n)
+            .await?
+            .into_iter()
+            .map(|(score, id, _)| (score, id))
+            .collect())
+    }
+}
+
+/// The index of whichever retrieval mode is configured
+pub enum SearchIndex<V> {
+    Vector(V),
+    Keyword(Bm25Index),
+    Hybrid(HybridIndex<V>),
+}
+
+impl<V: VectorStoreIndex> SearchIndex<V> {
+    /// Searches `vector`, a BM25 index over `documents`, or both; `documents` should be the chunks
+    /// `vector` holds
+    pub fn new(mode: SearchMode, vector: V, documents: Vec<Document>, rrf_k: f64) -> Self {
+        match mode {
+            SearchMode::Vector => Self::Vector(vector),
+            SearchMode::Keyword => Self::Keyword(Bm25Index::new(documents)),
+            SearchMode::Hybrid => {
+                Self::Hybrid(HybridIndex::new(vector, Bm25Index::new(documents), rrf_k))
+            }
+        }
+    }
+}
+
+impl<V: VectorStoreIndex> VectorStoreIndex for SearchIndex<V> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        match self {
+            Self::Vector(index) => index.top_n(query, n).await,
+            Self::Keyword(index) => index.top_n(query, n).await,
+            Self::Hybrid(index) => index.top_n(query, n).await,
+        }
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        match self {
+            Self::Vector(index) => index.top_n_ids(query, n).await,
+            Self::Keyword(index) => index.top_n_ids(query, n).await,
+            Self::Hybrid(index) => index.top_n_ids(query, n).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::{hashed, mock};
+    use crate::testing::{document, FixedResults};
+    use rig::agent::AgentBuilder;
+    use rig::completion::Completion;
+    use serde_json::json;
+
+    fn ranking(ids: &[&str]) -> Results {
+        ids.iter()
+            .map(|id| (0.0, id.to_string(), json!({ "id": id })))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_ranked_well_by_both_come_first() {
+        let fused = fuse(
+            [ranking(&["a", "b", "c"]), ranking(&["b", "d", "a"])],
+            DEFAULT_RRF_K,
+            4,
+        );
+        let ids: Vec<&str> = fused.iter().map(|(_, id, _)| id.as_str()).collect();
+
+        // b is first and second; a is first but only third in the other ranking
+        assert_eq!(ids, ["b", "a", "d", "c"]);
+        assert!((fused[0].0 - (1.0 / 61.0 + 1.0 / 62.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fusion_keeps_at_most_n_results() {
+        let fused = fuse([ranking(&["a", "b", "c"]), ranking(&[])], DEFAULT_RRF_K, 2);
+        let ids: Vec<&str> = fused.iter().map(|(_, id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn hybrid_fuses_vector_and_keyword_rankings() {
+        let documents = vec![
+            document("a", "Prune roses in early spring."),
+            document("b", "Water the tomato plants twice a week."),
+            document("c", "Rake the leaves in autumn."),
+        ];
+        // Best vector match a, then b, then c
+        let vector = FixedResults(
+            [("a", 0.9), ("b", 0.5), ("c", 0.1)]
+                .into_iter()
+                .map(|(id, score)| {
+                    let document = documents.iter().find(|d| d.id == id).unwrap();
+                    (
+                        score,
+                        id.to_string(),
+                        serde_json::to_value(document).unwrap(),
+                    )
+                })
+                .collect(),
+        );
+        let index = HybridIndex::new(vector, Bm25Index::new(documents), DEFAULT_RRF_K);
+
+        let ids: Vec<String> = index
+            .top_n_ids("tomato", 3)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect();
+
+        // b is second by vector and first by keyword; a only leads the vector ranking
+        assert_eq!(ids, ["b", "a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn vector_scores_are_cosine_similarities() {
+        let model = hashed::EmbeddingModel::default();
+        let texts = [
+            ("a", "Prune roses in early spring."),
+            ("b", "Water the tomato plants twice a week."),
+            ("c", "Rake the leaves in autumn."),
+        ];
+        let mut chunks = Vec::new();
+        for (id, text) in texts {
+            let embedding = model.embed_text(text).await.unwrap();
+            chunks.push((document(id, text), OneOrMany::one(embedding)));
+        }
+        let index = VectorIndex::new(model, chunks);
+
+        let results = index
+            .top_n_ids("Water the tomato plants twice a week.", 3)
+            .await
+            .unwrap();
+
+        assert_eq!(results[0].1, "b");
+        assert!((results[0].0 - 1.0).abs() < 1e-9);
+        assert!(results.windows(2).all(|pair| pair[0].0 >= pair[1].0));
+    }
+
+    #[tokio::test]
+    async fn search_index_works_as_dynamic_context() {
+        let documents = vec![
+            document("a", "Prune roses in early spring."),
+            document("b", "Water the tomato plants twice a week."),
+        ];
+        let vector = FixedResults(
+            documents
+                .iter()
+                .map(|d| (0.5, d.id.clone(), serde_json::to_value(d).unwrap()))
+                .collect(),
+        );
+        let index = SearchIndex::new(SearchMode::Hybrid, vector, documents, DEFAULT_RRF_K);
+        let model = mock::CompletionModel::new(|_| "Twice a week.".into());
+        let agent = AgentBuilder::new(model.clone())
+            .dynamic_context(1, index)
+            .build();
+
+        agent
+            .completion("How often do tomatoes need water?", Vec::new())
+            .await
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(model.last_request().unwrap().document_ids(), ["b"]);
+    }
+
+    #[test]
+    fn cosine_ignores_vector_length() {
+        assert!((cosine_similarity(&[1.0, 1.0], &[3.0, 3.0]) - 1.0).abs() < 1e-12);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 2.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn modes_parse_and_display() {
+        for mode in ["vector", "keyword", "hybrid"] {
+            assert_eq!(mode.parse::<SearchMode>().unwrap().to_string(), mode);
+        }
+        assert!("bm25".parse::<SearchMode>().is_err());
+    }
+}
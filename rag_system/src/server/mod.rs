@@ -0,0 +1,416 @@
+// HTTP API over the same index and agent as the CLI, for web apps and bots
+use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream;
+use rig::completion::Message;
+use rig::embeddings::EmbeddingModel;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex, RwLock};
+
+use crate::chat;
+use crate::citations::{self, CitedAnswer};
+use crate::config::Config;
+use crate::memory;
+use crate::pipeline::{self, Rag};
+use crate::store::StoredIndex;
+use crate::streaming::StreamingCompletionModel;
+use crate::Document;
+
+mod openai;
+
+/// Everything the handlers share; the agent is replaced whenever `/ingest` rebuilds the index
+pub struct AppState<E, C: StreamingCompletionModel> {
+    config: Config,
+    embedding_model: E,
+    completion_model: C,
+    // Handlers answer from their own clone, so a long answer never holds up `/ingest`'s swap
+    rag: RwLock<Arc<Rag<C>>>,
+    // The indexed files, as of the last ingestion
+    documents: RwLock<Vec<IndexedFile>>,
+    // Only one ingestion at a time; questions keep being answered from the old index meanwhile
+    ingesting: Mutex<()>,
+}
+
+#[derive(Deserialize)]
+pub struct QueryRequest {
+    pub question: String,
+    // Answer with server-sent events instead of one JSON body
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    pub message: String,
+    // Earlier turns as returned by the previous `/chat` response
+    #[serde(default)]
+    pub history: Vec<Message>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Default, Deserialize)]
+pub struct IngestRequest {
+    // Embed every file again instead of only new and changed ones
+    #[serde(default)]
+    pub reindex: bool,
+}
+
+/// An answer with numbered citations and the chunks behind them
+#[derive(Serialize)]
+pub struct AnswerResponse {
+    pub answer: String,
+    pub citations: Vec<Source>,
+    // Chunks the agent was given but didn't cite
+    pub uncited: Vec<Source>,
+}
+
+#[derive(Serialize)]
+pub struct ChatResponse {
+    #[serde(flatten)]
+    pub answer: AnswerResponse,
+    // The request's history plus this turn, to send with the next message
+    pub history: Vec<Message>,
+}
+
+#[derive(Serialize)]
+pub struct Source {
+    // The `[n]` marker used in the answer; absent for uncited chunks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<usize>,
+    pub id: String,
+    pub source: String,
+    // "p. 3", "pp. 3-5" or the section heading path, if known
+    pub location: Option<String>,
+    pub content: String,
+}
+
+impl Source {
+    fn new(number: Option<usize>, document: Document) -> Self {
+        Self {
+            number,
+            location: citations::location(&document),
+            id: document.id,
+            source: document.source,
+            content: document.content,
+        }
+    }
+}
+
+impl From<CitedAnswer> for AnswerResponse {
+    fn from(answer: CitedAnswer) -> Self {
+        Self {
+            answer: answer.text,
+            citations: answer
+                .citations
+                .into_iter()
+                .map(|citation| Source::new(Some(citation.number), citation.document))
+                .collect(),
+            uncited: answer
+                .uncited
+                .into_iter()
+                .map(|document| Source::new(None, document))
+                .collect(),
+        }
+    }
+}
+
+/// One indexed file, as listed by `/documents`
+#[derive(Clone, Serialize)]
+pub struct IndexedFile {
+    pub id: String,
+    pub chunks: usize,
+}
+
+impl IndexedFile {
+    fn list(stored: &StoredIndex) -> Vec<Self> {
+        stored
+            .sources
+            .iter()
+            .map(|source| Self {
+                id: source.id.clone(),
+                chunks: source.chunks.len(),
+            })
+            .collect()
+    }
+}
+
+impl<E, C: StreamingCompletionModel> AppState<E, C> {
+    // The current agent; the lock is released before the caller starts answering
+    async fn rag(&self) -> Arc<Rag<C>> {
+        self.rag.read().await.clone()
+    }
+}
+
+/// Errors are returned as `{"error": "..."}` with a matching status code
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+fn bad_request(message: &str) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, message.to_string())
+}
+
+/// Brings the index up to date, builds the agent and returns the API's routes
+pub async fn router<E, C>(
+    config: Config,
+    embedding_model: E,
+    completion_model: C,
+    reindex: bool,
+) -> Result<Router>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    let stored = pipeline::build_index(embedding_model.clone(), &config, reindex).await?;
+    let files = IndexedFile::list(&stored);
+    let rag = pipeline::agent(
+        stored,
+        embedding_model.clone(),
+        completion_model.clone(),
+        &config,
+    )?;
+
+    let state = Arc::new(AppState {
+        config,
+        embedding_model,
+        completion_model,
+        rag: RwLock::new(Arc::new(rag)),
+        documents: RwLock::new(files),
+        ingesting: Mutex::new(()),
+    });
+
+    Ok(Router::new()
+        .route("/health", get(health))
+        .route("/query", post(query::<E, C>))
+        .route("/chat", post(chat::<E, C>))
+        .route("/ingest", post(ingest::<E, C>))
+        .route("/documents", get(documents::<E, C>))
+        .route(
+            "/v1/chat/completions",
+            post(openai::chat_completions::<E, C>),
+        )
+        .route("/v1/models", get(openai::models::<E, C>))
+        .with_state(state))
+}
+
+/// Serves `router` on `addr` until the process is stopped
+pub async fn serve(router: Router, addr: SocketAddr) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+async fn health() -> Json<serde_json::Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+// One question without history
+async fn query<E, C>(
+    State(state): State<Arc<AppState<E, C>>>,
+    Json(request): Json<QueryRequest>,
+) -> Result<Response, ApiError>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    let question = request.question.trim();
+    if question.is_empty() {
+        return Err(bad_request("question must not be empty"));
+    }
+
+    if request.stream {
+        let question = question.to_string();
+        return Ok(stream_answer(state, question, Vec::new(), |_, answer| {
+            json!(AnswerResponse::from(answer))
+        }));
+    }
+
+    let rag = state.rag().await;
+    let (_, answer) = chat::ask(&rag, question, Vec::new()).await?;
+    Ok(Json(AnswerResponse::from(answer)).into_response())
+}
+
+// One turn of a conversation whose history the client keeps
+async fn chat<E, C>(
+    State(state): State<Arc<AppState<E, C>>>,
+    Json(request): Json<ChatRequest>,
+) -> Result<Response, ApiError>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    let message = request.message.trim();
+    if message.is_empty() {
+        return Err(bad_request("message must not be empty"));
+    }
+
+    let message = message.to_string();
+    let history = request.history;
+    if request.stream {
+        let sent = history.clone();
+        return Ok(stream_answer(
+            state,
+            message.clone(),
+            history,
+            move |response, answer| json!(chat_response(sent, &message, response, answer)),
+        ));
+    }
+
+    let rag = state.rag().await;
+    let (response, answer) = chat::ask(&rag, &message, history.clone()).await?;
+    Ok(Json(chat_response(history, &message, response, answer)).into_response())
+}
+
+fn chat_response(
+    mut history: Vec<Message>,
+    message: &str,
+    response: String,
+    answer: CitedAnswer,
+) -> ChatResponse {
+    memory::record_turn(&mut history, message, response);
+
+    ChatResponse {
+        answer: answer.into(),
+        history,
+    }
+}
+
+// Answers as server-sent events: a `token` event `{"text": "..."}` per piece of the numbered
+// answer, then a `done` event with what `done` builds from the raw response and the cited
+// answer, or an `error` event `{"error": "..."}`
+fn stream_answer<E, C>(
+    state: Arc<AppState<E, C>>,
+    question: String,
+    history: Vec<Message>,
+    done: impl FnOnce(String, CitedAnswer) -> serde_json::Value + Send + 'static,
+) -> Response
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    let token = |text: &str| {
+        Event::default()
+            .event("token")
+            .data(json!({ "text": text }).to_string())
+    };
+    let finish = move |result: Result<(String, CitedAnswer)>| {
+        vec![match result {
+            Ok((response, answer)) => Event::default()
+                .event("done")
+                .data(done(response, answer).to_string()),
+            Err(e) => Event::default()
+                .event("error")
+                .data(json!({ "error": format!("{e:#}") }).to_string()),
+        }]
+    };
+    sse(state, question, history, token, finish)
+}
+
+// Streams the answer as server-sent events: the event `token` makes of each piece of the
+// numbered answer, then the events `finish` makes of the outcome
+fn sse<E, C>(
+    state: Arc<AppState<E, C>>,
+    question: String,
+    history: Vec<Message>,
+    token: impl Fn(&str) -> Event + Send + 'static,
+    finish: impl FnOnce(Result<(String, CitedAnswer)>) -> Vec<Event> + Send + 'static,
+) -> Response
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    let (events, received) = mpsc::unbounded_channel();
+
+    // The answer is written in its own task so the events can be sent while it's written
+    tokio::spawn(async move {
+        let rag = state.rag().await;
+        let tokens = events.clone();
+        let result = chat::ask_streaming(&rag, &question, history, move |text| {
+            tokens.send(token(text)).ok();
+        })
+        .await;
+
+        for event in finish(result) {
+            events.send(event).ok();
+        }
+    });
+
+    let events = stream::unfold(received, |mut received| async move {
+        let event = received.recv().await?;
+        Some((Ok::<_, Infallible>(event), received))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+// Re-scans the documents directory and swaps in an agent over the updated index
+async fn ingest<E, C>(
+    State(state): State<Arc<AppState<E, C>>>,
+    body: Bytes,
+) -> Result<Json<serde_json::Value>, ApiError>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    // The body is optional, but one that's there has to be a valid request
+    let request: IngestRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        IngestRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| bad_request(&format!("Invalid request body: {e}")))?
+    };
+    let _ingesting = state.ingesting.lock().await;
+
+    let stored = pipeline::build_index(
+        state.embedding_model.clone(),
+        &state.config,
+        request.reindex,
+    )
+    .await?;
+    let (files, chunks) = (stored.sources.len(), stored.entries.len());
+    let documents = IndexedFile::list(&stored);
+    let rag = pipeline::agent(
+        stored,
+        state.embedding_model.clone(),
+        state.completion_model.clone(),
+        &state.config,
+    )?;
+    // Questions already being answered finish with the agent they started with
+    *state.rag.write().await = Arc::new(rag);
+    *state.documents.write().await = documents;
+
+    Ok(Json(json!({ "files": files, "chunks": chunks })))
+}
+
+// The indexed files and their chunk counts, as of the last ingestion
+async fn documents<E, C>(State(state): State<Arc<AppState<E, C>>>) -> Json<serde_json::Value>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    let documents = state.documents.read().await.clone();
+    Json(json!({ "documents": documents }))
+}
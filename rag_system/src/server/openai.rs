@@ -0,0 +1,243 @@
+// The agent behind OpenAI's chat completions API, so existing OpenAI clients can use it by
+// changing their base URL
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rig::completion::Message;
+use rig::embeddings::EmbeddingModel;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::AppState;
+use crate::chat;
+use crate::citations::CitedAnswer;
+use crate::streaming::StreamingCompletionModel;
+use crate::tokens;
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    // Echoed back; the configured chat model answers whatever is asked for
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: Option<Content>,
+}
+
+/// Message content as a string or as a list of parts, of which only text parts are read
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+pub struct ContentPart {
+    pub text: Option<String>,
+}
+
+impl ChatMessage {
+    fn text(&self) -> String {
+        match &self.content {
+            Some(Content::Text(text)) => text.clone(),
+            Some(Content::Parts(parts)) => parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => String::new(),
+        }
+    }
+}
+
+/// Errors in OpenAI's format, `{"error": {"message": "...", "type": "..."}}`
+pub struct OpenAiError(StatusCode, String);
+
+impl IntoResponse for OpenAiError {
+    fn into_response(self) -> Response {
+        let kind = if self.0.is_client_error() {
+            "invalid_request_error"
+        } else {
+            "server_error"
+        };
+        (self.0, Json(error_body(&self.1, kind))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for OpenAiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+fn error_body(message: &str, kind: &str) -> serde_json::Value {
+    json!({ "error": { "message": message, "type": kind } })
+}
+
+// The last message is the question; the ones before it are the conversation so far
+pub async fn chat_completions<E, C>(
+    State(state): State<Arc<AppState<E, C>>>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, OpenAiError>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    let Some((last, earlier)) = request.messages.split_last() else {
+        return Err(OpenAiError(
+            StatusCode::BAD_REQUEST,
+            "messages must not be empty".into(),
+        ));
+    };
+    let question = last.text().trim().to_string();
+    if last.role != "user" || question.is_empty() {
+        return Err(OpenAiError(
+            StatusCode::BAD_REQUEST,
+            "The last message must be a non-empty user message".into(),
+        ));
+    }
+    let history: Vec<Message> = earlier
+        .iter()
+        .map(|message| Message {
+            role: message.role.clone(),
+            content: message.text(),
+        })
+        .collect();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let completion = Completion {
+        id: format!("chatcmpl-{:x}", now.as_nanos()),
+        created: now.as_secs(),
+        model: request
+            .model
+            .unwrap_or_else(|| state.config.completion.model().to_string()),
+    };
+
+    if request.stream {
+        let chunks = completion.clone();
+        let token =
+            move |text: &str| chunks.chunk(json!({ "role": "assistant", "content": text }), None);
+        let finish =
+            move |result: Result<(String, CitedAnswer)>| match result {
+                Ok((_, answer)) => vec![
+                    completion.chunk(
+                        json!({ "content": format!("\n\n{}", footer(&answer)) }),
+                        None,
+                    ),
+                    completion.chunk(json!({}), Some("stop")),
+                    Event::default().data("[DONE]"),
+                ],
+                Err(e) => vec![Event::default()
+                    .data(error_body(&format!("{e:#}"), "server_error").to_string())],
+            };
+        return Ok(super::sse(state, question, history, token, finish));
+    }
+
+    let rag = state.rag().await;
+    let (response, answer) = chat::ask(&rag, &question, history).await?;
+    Ok(Json(json!({
+        "id": completion.id,
+        "object": "chat.completion",
+        "created": completion.created,
+        "model": completion.model,
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": format!("{}\n\n{}", answer.text, footer(&answer)),
+            },
+            "finish_reason": "stop",
+        }],
+        "usage": usage(&state.config.completion.preamble, &request.messages, &answer, &response),
+    }))
+    .into_response())
+}
+
+// Lists the one model this server offers, for clients that look it up before chatting
+pub async fn models<E, C>(State(state): State<Arc<AppState<E, C>>>) -> Json<serde_json::Value>
+where
+    E: EmbeddingModel + Sync + 'static,
+    C: StreamingCompletionModel + 'static,
+{
+    Json(json!({
+        "object": "list",
+        "data": [{
+            "id": state.config.completion.model(),
+            "object": "model",
+            "created": 0,
+            "owned_by": "rag_system",
+        }],
+    }))
+}
+
+// What every chunk of a streamed completion repeats
+#[derive(Clone)]
+struct Completion {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl Completion {
+    fn chunk(&self, delta: serde_json::Value, finish_reason: Option<&str>) -> Event {
+        Event::default().data(
+            json!({
+                "id": self.id,
+                "object": "chat.completion.chunk",
+                "created": self.created,
+                "model": self.model,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            })
+            .to_string(),
+        )
+    }
+}
+
+// Clients only show the message content, so the sources go at its end
+fn footer(answer: &CitedAnswer) -> String {
+    answer.footer().trim_end().to_string()
+}
+
+// Token counts estimated with cl100k_base, whatever the chat model's tokenizer. The prompt is
+// the preamble, the messages and the retrieved chunks, before the history is cut to the memory's
+// budget; the completion is the model's response without the sources footer. The extra call
+// condensing a follow-up isn't counted.
+fn usage(
+    preamble: &str,
+    messages: &[ChatMessage],
+    answer: &CitedAnswer,
+    response: &str,
+) -> serde_json::Value {
+    let chunks = answer
+        .citations
+        .iter()
+        .map(|citation| &citation.document)
+        .chain(&answer.uncited);
+    let prompt_tokens = tokens::count_tokens(preamble)
+        + messages
+            .iter()
+            .map(|message| tokens::count_tokens(&message.text()))
+            .sum::<usize>()
+        + chunks
+            .map(|document| tokens::count_tokens(&document.content))
+            .sum::<usize>();
+    let completion_tokens = tokens::count_tokens(response);
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+    })
+}
@@ -0,0 +1,257 @@
+// Saving and loading the embedded chunks so startup doesn't re-embed everything
+use anyhow::{Context, Result};
+use rig::{embeddings::Embedding, OneOrMany};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+use crate::Document;
+
+// Bump whenever the layout below changes; older files are rebuilt instead of loaded
+pub const FORMAT_VERSION: u32 = 4;
+
+// File name of the index inside the index directory
+pub const INDEX_FILE: &str = "index.json";
+
+/// Everything needed to rebuild the search index without calling the embedding API
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredIndex {
+    pub version: u32,
+    // Vectors from different models can't be compared, so the model is recorded with them
+    pub embedding_model: String,
+    // Chunking settings the entries were produced with; changing them invalidates every chunk
+    #[serde(default)]
+    pub chunking: String,
+    // Missing in version 1 files, which are rebuilt anyway
+    #[serde(default)]
+    pub sources: Vec<SourceRecord>,
+    pub entries: Vec<StoredEntry>,
+}
+
+// The fields every format version has in common
+#[derive(Deserialize)]
+struct IndexHeader {
+    version: u32,
+    #[serde(default)]
+    embedding_model: String,
+    #[serde(default)]
+    chunking: String,
+}
+
+/// What the index knows about one source file, used to skip unchanged files on the next run
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceRecord {
+    pub id: String,
+    // SHA-256 of the file contents, hex encoded
+    pub hash: String,
+    // Modification time in nanoseconds since the Unix epoch, plus size; a cheap first check before hashing
+    pub modified_ns: u64,
+    pub size: u64,
+    // Ids of the chunks produced from this file
+    pub chunks: Vec<String>,
+}
+
+/// One chunk and the embeddings generated for it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredEntry {
+    pub document: Document,
+    pub embeddings: Vec<StoredEmbedding>,
+}
+
+impl StoredEntry {
+    /// Captures one item of the output of `EmbeddingsBuilder::build`
+    pub fn new(document: Document, embeddings: &OneOrMany<Embedding>) -> Self {
+        Self {
+            document,
+            embeddings: embeddings
+                .iter()
+                .map(|embedding| StoredEmbedding {
+                    text: embedding.document.clone(),
+                    vector: embedding.vec.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredEmbedding {
+    pub text: String,
+    pub vector: Vec<f64>,
+}
+
+impl StoredIndex {
+    /// Creates an index with no sources or chunks
+    pub fn empty(embedding_model: &str, chunking: &str) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            embedding_model: embedding_model.to_string(),
+            chunking: chunking.to_string(),
+            sources: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Whether this index can be updated in place with the given embedding model and chunking
+    pub fn is_compatible(&self, embedding_model: &str, chunking: &str) -> bool {
+        self.version == FORMAT_VERSION
+            && self.embedding_model == embedding_model
+            && self.chunking == chunking
+    }
+
+    /// Turns the stored entries back into the chunks and embeddings the vector index is built from
+    pub fn into_embeddings(self) -> Result<Vec<(Document, OneOrMany<Embedding>)>> {
+        self.entries
+            .into_iter()
+            .map(|entry| {
+                let embeddings = entry
+                    .embeddings
+                    .into_iter()
+                    .map(|embedding| Embedding {
+                        document: embedding.text,
+                        vec: embedding.vector,
+                    })
+                    .collect();
+
+                let embeddings = OneOrMany::many(embeddings)
+                    .with_context(|| format!("Chunk {} has no embeddings", entry.document.id))?;
+
+                Ok((entry.document, embeddings))
+            })
+            .collect()
+    }
+
+    /// Loads the index from `dir`, or returns `None` if none has been saved yet.
+    ///
+    /// Files written with another format version are returned without sources or entries,
+    /// since their contents may not parse; `is_compatible` is false for them.
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let json =
+            std::fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+
+        let header: IndexHeader =
+            serde_json::from_str(&json).with_context(|| format!("Failed to parse {:?}", path))?;
+        if header.version != FORMAT_VERSION {
+            let mut index = Self::empty(&header.embedding_model, &header.chunking);
+            index.version = header.version;
+            return Ok(Some(index));
+        }
+
+        let index =
+            serde_json::from_str(&json).with_context(|| format!("Failed to parse {:?}", path))?;
+
+        Ok(Some(index))
+    }
+
+    /// Writes the index to `dir`, replacing any previous one
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+
+        // Write to a temporary file first so an interrupted save never leaves a truncated index
+        let path = dir.join(INDEX_FILE);
+        let tmp_path = dir.join(format!("{INDEX_FILE}.tmp"));
+
+        let file = std::fs::File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {:?}", tmp_path))?;
+        let mut writer = std::io::BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        std::fs::rename(&tmp_path, &path).with_context(|| format!("Failed to write {:?}", path))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    use crate::testing::document;
+
+    fn index() -> StoredIndex {
+        let mut index = StoredIndex::empty("text-embedding-ada-002", "2000 chars");
+        index.sources.push(SourceRecord {
+            id: "guide.md".to_string(),
+            hash: "abc123".to_string(),
+            modified_ns: 42,
+            size: 17,
+            chunks: vec!["guide.md#0".to_string()],
+        });
+        index.entries.push(StoredEntry {
+            document: document("guide.md#0", "Install with cargo."),
+            embeddings: vec![StoredEmbedding {
+                text: "Install with cargo.".to_string(),
+                vector: vec![0.25, -0.5, 1.0],
+            }],
+        });
+        index
+    }
+
+    #[test]
+    fn saved_index_loads_back() {
+        let dir = TempDir::new().unwrap();
+        assert!(StoredIndex::load(dir.path()).unwrap().is_none());
+
+        index().save(dir.path()).unwrap();
+        let loaded = StoredIndex::load(dir.path()).unwrap().unwrap();
+
+        assert!(loaded.is_compatible("text-embedding-ada-002", "2000 chars"));
+        assert_eq!(loaded.sources[0].hash, "abc123");
+        assert_eq!(loaded.sources[0].chunks, ["guide.md#0"]);
+        let embeddings = loaded.into_embeddings().unwrap();
+        assert_eq!(
+            embeddings[0].0,
+            document("guide.md#0", "Install with cargo.")
+        );
+        assert_eq!(embeddings[0].1.first().vec, [0.25, -0.5, 1.0]);
+    }
+
+    #[test]
+    fn older_format_versions_load_empty_and_incompatible() {
+        let dir = TempDir::new().unwrap();
+        // A layout the current version can't parse
+        let old = serde_json::json!({
+            "version": FORMAT_VERSION - 1,
+            "embedding_model": "text-embedding-ada-002",
+            "chunking": "2000 chars",
+            "entries": [{ "id": "guide.md#0", "text": "Install with cargo." }],
+        });
+        std::fs::write(dir.path().join(INDEX_FILE), old.to_string()).unwrap();
+
+        let loaded = StoredIndex::load(dir.path()).unwrap().unwrap();
+
+        assert_eq!(loaded.version, FORMAT_VERSION - 1);
+        assert!(loaded.entries.is_empty());
+        assert!(!loaded.is_compatible("text-embedding-ada-002", "2000 chars"));
+    }
+
+    #[test]
+    fn save_replaces_the_index_without_leaving_a_temporary_file() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join(INDEX_FILE), "truncated {").unwrap();
+        // Left over from a save that was interrupted
+        std::fs::write(dir.path().join(format!("{INDEX_FILE}.tmp")), "partial").unwrap();
+
+        index().save(dir.path()).unwrap();
+
+        let files: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(files, [INDEX_FILE]);
+        assert_eq!(
+            StoredIndex::load(dir.path())
+                .unwrap()
+                .unwrap()
+                .entries
+                .len(),
+            1
+        );
+    }
+}
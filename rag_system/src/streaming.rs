@@ -0,0 +1,18 @@
+// Answers delivered piece by piece as the model writes them; rig's completion models only
+// return whole responses
+use anyhow::Result;
+use futures::Stream;
+use rig::completion::{CompletionModel, CompletionRequest};
+use std::future::Future;
+use std::pin::Pin;
+
+/// Pieces of an answer in the order the model writes them
+pub type TokenStream = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+
+/// A chat model that can stream its answer to a completion request
+pub trait StreamingCompletionModel: CompletionModel {
+    fn stream(
+        &self,
+        request: CompletionRequest,
+    ) -> impl Future<Output = Result<TokenStream>> + Send;
+}
@@ -0,0 +1,53 @@
+// Test doubles and fixtures shared by the unit tests
+use rig::vector_store::{VectorStoreError, VectorStoreIndex};
+use serde::Deserialize;
+
+use crate::Document;
+
+/// An index that returns the results it was given, in the order given, whatever the query
+pub struct FixedResults(pub Vec<(f64, String, serde_json::Value)>);
+
+impl VectorStoreIndex for FixedResults {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        _query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        self.0
+            .iter()
+            .take(n)
+            .map(|(score, id, document)| {
+                Ok((
+                    *score,
+                    id.clone(),
+                    serde_json::from_value(document.clone())?,
+                ))
+            })
+            .collect()
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        let results = self.top_n::<serde_json::Value>(query, n).await?;
+        Ok(results
+            .into_iter()
+            .map(|(score, id, _)| (score, id))
+            .collect())
+    }
+}
+
+/// A chunk with no page or section, whose source is the part of `id` before any `#`
+pub fn document(id: &str, content: &str) -> Document {
+    Document {
+        id: id.to_string(),
+        source: id.split('#').next().unwrap_or(id).to_string(),
+        page_start: None,
+        page_end: None,
+        section: None,
+        ordinal: 0,
+        content: content.to_string(),
+    }
+}
@@ -0,0 +1,17 @@
+// Token counting with the cl100k_base BPE used by text-embedding-ada-002 and GPT-4
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+// Longest input text-embedding-ada-002 accepts
+pub const MAX_EMBEDDING_TOKENS: usize = 8191;
+
+// The vocabulary is bundled with tiktoken-rs, so this works offline; it's built once and shared
+fn bpe() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| tiktoken_rs::cl100k_base().expect("bundled cl100k_base vocabulary is valid"))
+}
+
+/// Number of tokens the model sees for `text`
+pub fn count_tokens(text: &str) -> usize {
+    bpe().encode_ordinary(text).len()
+}
@@ -0,0 +1,38 @@
+// Fixtures shared by the integration tests
+use std::fs;
+use std::path::Path;
+
+use rag_system::config::Config;
+use tempfile::TempDir;
+
+pub const BAKING: &str =
+    "Sourdough bread needs a long proof. Let the dough rise for four to six hours \
+at room temperature, or overnight in the fridge, before baking it in a hot oven.";
+
+pub const SAILING: &str = "When sailing upwind, trim the jib until its telltales stream evenly. \
+Reef the mainsail early if the wind picks up and the boat heels too far.";
+
+pub const GARDENING: &str = "Tomato plants want full sun and deep watering twice a week. \
+Pinch off the side shoots so the plant puts its energy into the fruit.";
+
+// A documents directory with a baking and a sailing file, each small enough to be a single
+// chunk, and a config pointing at it with the index saved next to it
+pub fn setup() -> (TempDir, Config) {
+    let dir = TempDir::new().unwrap();
+    let documents = dir.path().join("documents");
+    fs::create_dir(&documents).unwrap();
+    fs::write(documents.join("baking.md"), BAKING).unwrap();
+    fs::write(documents.join("sailing.txt"), SAILING).unwrap();
+
+    let mut config = Config::default();
+    set(&mut config, "documents.dir", &documents);
+    set(&mut config, "index.dir", &dir.path().join("index"));
+    config.set("embedding.provider", "hashed").unwrap();
+    config.validate().unwrap();
+
+    (dir, config)
+}
+
+fn set(config: &mut Config, key: &str, path: &Path) {
+    config.set(key, path.to_str().unwrap()).unwrap();
+}
@@ -0,0 +1,506 @@
+// End-to-end tests of the RAG agent: real loading, chunking, hashed embeddings and retrieval,
+// with a mock chat model recording what the agent sends it
+use std::fs;
+
+use rig::completion::Message;
+use rig::embeddings::EmbeddingModel as _;
+
+use rag_system::chat;
+use rag_system::citations::CITATION_INSTRUCTIONS;
+use rag_system::config::Config;
+use rag_system::grounding;
+use rag_system::pipeline::{self, Rag};
+use rag_system::providers::hashed;
+use rag_system::providers::mock::{self, RecordedRequest};
+use tempfile::TempDir;
+
+mod common;
+
+use common::{BAKING, GARDENING, SAILING};
+
+// The shared baking and sailing files plus a gardening one, so each topic has one chunk
+fn setup() -> (TempDir, Config) {
+    let (dir, config) = common::setup();
+    fs::write(config.documents.dir.join("gardening.md"), GARDENING).unwrap();
+    (dir, config)
+}
+
+// Builds the agent the way the CLI does, but with hashed embeddings and the mock chat model
+async fn agent(config: &Config, model: &mock::CompletionModel) -> Rag<mock::CompletionModel> {
+    pipeline::build_agent(
+        hashed::EmbeddingModel::default(),
+        model.clone(),
+        config,
+        false,
+    )
+    .await
+    .unwrap()
+}
+
+async fn ask(config: &Config, model: &mock::CompletionModel, question: &str) -> RecordedRequest {
+    let rag = agent(config, model).await;
+    chat::ask(&rag, question, Vec::new()).await.unwrap();
+    model.last_request().unwrap()
+}
+
+#[tokio::test]
+async fn agent_is_given_the_matching_chunk() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "1").unwrap();
+    let model = mock::CompletionModel::new(|_| "Four to six hours.".into());
+
+    let request = ask(&config, &model, "How long should sourdough dough proof?").await;
+
+    assert_eq!(request.document_ids(), ["baking.md#0"]);
+    assert!(request.documents[0].text.contains("four to six hours"));
+    assert_eq!(request.prompt, "How long should sourdough dough proof?");
+    assert!(request.preamble.unwrap().ends_with(CITATION_INSTRUCTIONS));
+}
+
+#[tokio::test]
+async fn each_question_retrieves_its_own_chunks() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "1").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+
+    for (question, expected) in [
+        (
+            "How do I trim the jib when sailing upwind?",
+            "sailing.txt#0",
+        ),
+        (
+            "How often should tomato plants be watered?",
+            "gardening.md#0",
+        ),
+        ("How long does sourdough bread rise?", "baking.md#0"),
+    ] {
+        let request = ask(&config, &model, question).await;
+        assert_eq!(request.document_ids(), [expected], "for {question:?}");
+    }
+    assert_eq!(model.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn top_k_chunks_are_given_best_match_first() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "3").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+    let question = "When should I reef the mainsail?";
+
+    let request = ask(&config, &model, question).await;
+
+    // Every chunk, ordered by the similarity of its embedding to the question's
+    let embedder = hashed::EmbeddingModel::default();
+    let question = embedder.embed_text(question).await.unwrap().vec;
+    let mut expected = Vec::new();
+    for (id, text) in [
+        ("baking.md#0", BAKING),
+        ("sailing.txt#0", SAILING),
+        ("gardening.md#0", GARDENING),
+    ] {
+        let chunk = embedder.embed_text(text).await.unwrap().vec;
+        let similarity: f64 = question.iter().zip(&chunk).map(|(a, b)| a * b).sum();
+        expected.push((similarity, id));
+    }
+    expected.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let expected: Vec<&str> = expected.into_iter().map(|(_, id)| id).collect();
+    assert_eq!(request.document_ids(), expected);
+    assert_eq!(expected[0], "sailing.txt#0");
+}
+
+#[tokio::test]
+async fn context_budget_drops_lower_ranked_chunks() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "3").unwrap();
+    // Room for one chunk as the agent sees it (pretty-printed JSON), but not two
+    config.set("retrieval.context_tokens", "90").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+
+    let request = ask(
+        &config,
+        &model,
+        "How often should tomato plants be watered?",
+    )
+    .await;
+
+    assert_eq!(request.document_ids(), ["gardening.md#0"]);
+}
+
+#[tokio::test]
+async fn keyword_and_hybrid_modes_find_exact_identifiers() {
+    let (dir, mut config) = setup();
+    fs::write(
+        dir.path().join("documents/parts.txt"),
+        "Spare parts: the pump seal is KX-4471-B and the filter cartridge is FC-20.",
+    )
+    .unwrap();
+    config.set("retrieval.top_k", "1").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+
+    for mode in ["keyword", "hybrid"] {
+        config.set("retrieval.mode", mode).unwrap();
+        let request = ask(&config, &model, "Where is KX-4471-B used?").await;
+        assert_eq!(request.document_ids(), ["parts.txt#0"], "in {mode} mode");
+    }
+}
+
+#[tokio::test]
+async fn llm_reranker_picks_from_the_wider_candidate_set() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "1").unwrap();
+    config.set("retrieval.candidates", "3").unwrap();
+    config.set("retrieval.rerank", "llm").unwrap();
+    // As reranker, rate the tomato passage highest whatever the question
+    let model = mock::CompletionModel::new(|request| {
+        if request.documents.is_empty() {
+            let passages = request.prompt.split("\nPassage ").skip(1);
+            passages
+                .map(|passage| {
+                    let (number, text) = passage.split_once(':').unwrap();
+                    let score = if text.contains("Tomato") { 10 } else { 1 };
+                    format!("{number}: {score}\n")
+                })
+                .collect()
+        } else {
+            "Answer".into()
+        }
+    });
+
+    let request = ask(&config, &model, "How long does sourdough rise?").await;
+
+    assert_eq!(request.document_ids(), ["gardening.md#0"]);
+    let requests = model.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].prompt.matches("\nPassage ").count(), 3);
+}
+
+#[tokio::test]
+async fn mmr_skips_near_duplicate_chunks() {
+    let (dir, mut config) = setup();
+    fs::write(
+        dir.path().join("documents/baking-copy.md"),
+        BAKING.replace("hot oven", "very hot oven"),
+    )
+    .unwrap();
+    config.set("retrieval.top_k", "2").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+    let question = "How long should sourdough bread rise before baking?";
+
+    let request = ask(&config, &model, question).await;
+    let mut ids = request.document_ids();
+    ids.sort();
+    assert_eq!(ids, ["baking-copy.md#0", "baking.md#0"]);
+
+    config.set("retrieval.mmr", "true").unwrap();
+    let request = ask(&config, &model, question).await;
+    assert_eq!(request.documents.len(), 2);
+    assert!(request.document_ids()[0].starts_with("baking"));
+    assert!(!request.document_ids()[1].starts_with("baking"));
+}
+
+#[tokio::test]
+async fn min_score_drops_unrelated_chunks() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "3").unwrap();
+    config.set("retrieval.min_score", "0.2").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+
+    let request = ask(&config, &model, "How long should sourdough bread rise?").await;
+
+    assert_eq!(request.document_ids(), ["baking.md#0"]);
+}
+
+#[tokio::test]
+async fn refuses_without_asking_the_model_when_nothing_is_relevant() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.min_score", "0.2").unwrap();
+    config.set("retrieval.no_context", "refuse").unwrap();
+    let model = mock::CompletionModel::new(|_| "From general knowledge".into());
+    let rag = agent(&config, &model).await;
+
+    let (_, answer) = chat::ask(
+        &rag,
+        "What was the quarterly revenue of Acme Corp?",
+        Vec::new(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(answer.text, grounding::REFUSAL);
+    assert_eq!(answer.footer(), "No relevant documents found.\n");
+    assert!(model.requests().is_empty());
+}
+
+#[tokio::test]
+async fn model_is_told_when_the_documents_have_nothing() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.min_score", "0.2").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+
+    for (behavior, instructions) in [
+        (
+            "not_found",
+            "Tell the user that the documents don't cover it.",
+        ),
+        ("fallback", "Answer from general knowledge"),
+    ] {
+        config.set("retrieval.no_context", behavior).unwrap();
+        let request = ask(
+            &config,
+            &model,
+            "What was the quarterly revenue of Acme Corp?",
+        )
+        .await;
+
+        assert!(request.documents.is_empty());
+        let preamble = request.preamble.unwrap();
+        assert!(preamble.starts_with(rag_system::config::DEFAULT_PREAMBLE));
+        assert!(preamble.contains(instructions), "for {behavior}");
+    }
+}
+
+#[tokio::test]
+async fn citations_refer_to_the_retrieved_chunks() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "2").unwrap();
+    let model = mock::CompletionModel::with_responses([
+        "Let it rise for four to six hours [baking.md#0]. Bake it hot [made-up.md#1].",
+    ]);
+    let rag = agent(&config, &model).await;
+
+    let (_, answer) = chat::ask(&rag, "How long does sourdough rise?", Vec::new())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        answer.text,
+        "Let it rise for four to six hours [1]. Bake it hot [made-up.md#1]."
+    );
+    assert_eq!(answer.citations.len(), 1);
+    assert_eq!(answer.citations[0].document.id, "baking.md#0");
+    assert_eq!(answer.citations[0].document.source, "baking.md");
+    // The other retrieved chunk is listed as uncited
+    assert_eq!(answer.uncited.len(), 1);
+    assert_ne!(answer.uncited[0].id, "baking.md#0");
+}
+
+#[tokio::test]
+async fn requests_carry_the_chunks_they_were_given() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "1").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+    let rag = agent(&config, &model).await;
+
+    // Both built before either is answered, as when the server handles them at once
+    let question = "How long does sourdough rise?";
+    let (first, first_chunks) = rag.request(question, question, Vec::new()).await.unwrap();
+    let (second, second_chunks) = rag.request(question, question, Vec::new()).await.unwrap();
+    second.send().await.unwrap();
+    first.send().await.unwrap();
+
+    assert_eq!(first_chunks[0].id, "baking.md#0");
+    assert_eq!(second_chunks[0].id, "baking.md#0");
+    for request in model.requests() {
+        assert_eq!(request.document_ids(), ["baking.md#0"]);
+    }
+}
+
+#[tokio::test]
+async fn streamed_answer_matches_the_whole_answer() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "3").unwrap();
+    let response = "Let it rise for four to six hours [baking.md#0], or overnight [baking.md#0].";
+    let model = mock::CompletionModel::new(move |_| response.into());
+    let rag = agent(&config, &model).await;
+    let question = "How long does sourdough rise?";
+
+    let (whole, whole_answer) = chat::ask(&rag, question, Vec::new()).await.unwrap();
+    let whole_request = model.last_request().unwrap();
+    let mut pieces = Vec::new();
+    let (streamed, answer) = chat::ask_streaming(&rag, question, Vec::new(), |text| {
+        pieces.push(text.to_string())
+    })
+    .await
+    .unwrap();
+    let streamed_request = model.last_request().unwrap();
+
+    assert!(pieces.len() > 1);
+    assert_eq!(pieces.concat(), answer.text);
+    assert_eq!(
+        answer.text,
+        "Let it rise for four to six hours [1], or overnight [1]."
+    );
+    assert_eq!(streamed, response);
+    assert_eq!(streamed, whole);
+    assert_eq!(answer.text, whole_answer.text);
+    // Both requests were given the same chunks in the same order, best match first
+    assert_eq!(streamed_request.document_ids().len(), 3);
+    assert_eq!(streamed_request.document_ids()[0], "baking.md#0");
+    assert_eq!(
+        streamed_request.document_ids(),
+        whole_request.document_ids()
+    );
+    let ids = |documents: &[rag_system::Document]| -> Vec<String> {
+        documents
+            .iter()
+            .map(|document| document.id.clone())
+            .collect()
+    };
+    assert_eq!(ids(&answer.uncited), ids(&whole_answer.uncited));
+}
+
+#[tokio::test]
+async fn chat_history_is_passed_to_the_model() {
+    let (_dir, mut config) = setup();
+    // Only the answers use up canned responses
+    config.set("memory.condense", "false").unwrap();
+    let model = mock::CompletionModel::with_responses(["Four to six hours.", "Yes, overnight."]);
+    let rag = agent(&config, &model).await;
+
+    let (first, _) = chat::ask(&rag, "How long does sourdough rise?", Vec::new())
+        .await
+        .unwrap();
+    let history = vec![
+        Message {
+            role: "user".into(),
+            content: "How long does sourdough rise?".into(),
+        },
+        Message {
+            role: "assistant".into(),
+            content: first,
+        },
+    ];
+    chat::ask(&rag, "Can it rise in the fridge?", history)
+        .await
+        .unwrap();
+
+    let requests = model.requests();
+    assert!(requests[0].chat_history.is_empty());
+    assert_eq!(requests[1].chat_history.len(), 2);
+    assert_eq!(requests[1].chat_history[1].content, "Four to six hours.");
+
+    // The canned responses are used up
+    assert!(chat::ask(&rag, "And then?", Vec::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn follow_up_retrieves_with_the_condensed_question() {
+    let (_dir, mut config) = setup();
+    config.set("retrieval.top_k", "1").unwrap();
+    let model = mock::CompletionModel::new(|request| {
+        let condensing = request
+            .preamble
+            .as_deref()
+            .is_some_and(|preamble| preamble.contains("standalone question"));
+        if condensing {
+            "How long should sourdough dough proof in the fridge?".into()
+        } else {
+            "Overnight.".into()
+        }
+    });
+    let rag = agent(&config, &model).await;
+
+    let history = vec![
+        Message {
+            role: "user".into(),
+            content: "How long should sourdough dough proof?".into(),
+        },
+        Message {
+            role: "assistant".into(),
+            content: "Four to six hours.".into(),
+        },
+    ];
+    chat::ask(&rag, "And what about after that?", history)
+        .await
+        .unwrap();
+
+    let requests = model.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].prompt.contains("Assistant: Four to six hours."));
+    assert!(requests[0]
+        .prompt
+        .ends_with("Follow-up question: And what about after that?"));
+    // The answer is asked for with the original question but the condensed one's chunks
+    assert_eq!(requests[1].prompt, "And what about after that?");
+    assert_eq!(requests[1].document_ids(), ["baking.md#0"]);
+}
+
+#[tokio::test]
+async fn history_is_cut_to_the_token_budget() {
+    let (_dir, mut config) = setup();
+    config.set("memory.condense", "false").unwrap();
+    config.set("memory.history_tokens", "10").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+    let rag = agent(&config, &model).await;
+
+    let history = vec![
+        Message {
+            role: "user".into(),
+            content: "Tell me everything there is to know about proofing sourdough.".into(),
+        },
+        Message {
+            role: "assistant".into(),
+            content:
+                "Proof it for four to six hours at room temperature, or overnight in the fridge."
+                    .into(),
+        },
+        Message {
+            role: "user".into(),
+            content: "Thanks!".into(),
+        },
+        Message {
+            role: "assistant".into(),
+            content: "You're welcome.".into(),
+        },
+    ];
+    chat::ask(&rag, "When do I water tomatoes?", history)
+        .await
+        .unwrap();
+
+    let sent = model.last_request().unwrap();
+    let contents: Vec<&str> = sent
+        .chat_history
+        .iter()
+        .map(|m| m.content.as_str())
+        .collect();
+    assert_eq!(contents, ["Thanks!", "You're welcome."]);
+}
+
+#[tokio::test]
+async fn saved_index_is_reused_and_updated() {
+    let (dir, mut config) = setup();
+    config.set("retrieval.top_k", "1").unwrap();
+    let model = mock::CompletionModel::new(|_| "Answer".into());
+
+    ask(&config, &model, "How long does sourdough rise?").await;
+    assert!(dir.path().join("index").exists());
+
+    // A new file is picked up on the next run
+    fs::write(
+        dir.path().join("documents/cycling.txt"),
+        "Check the bicycle tyre pressure before every ride and oil the chain monthly.",
+    )
+    .unwrap();
+    let stored = pipeline::build_index(hashed::EmbeddingModel::default(), &config, false)
+        .await
+        .unwrap();
+    assert_eq!(stored.sources.len(), 4);
+
+    let request = ask(&config, &model, "How often should I oil the bicycle chain?").await;
+    assert_eq!(request.document_ids(), ["cycling.txt#0"]);
+}
+
+#[tokio::test]
+async fn files_without_text_do_not_stop_ingestion() {
+    let (dir, config) = setup();
+    fs::write(dir.path().join("documents/notes.md"), "\n").unwrap();
+
+    let stored = pipeline::build_index(hashed::EmbeddingModel::default(), &config, false)
+        .await
+        .unwrap();
+
+    let notes = stored.sources.iter().find(|source| source.id == "notes.md");
+    assert!(notes.unwrap().chunks.is_empty());
+    assert_eq!(stored.entries.len(), 3);
+}
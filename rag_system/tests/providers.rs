@@ -0,0 +1,331 @@
+// Tests of the HTTP providers against a local stand-in server that records each request and
+// answers with a canned response
+use std::sync::{Arc, Mutex};
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::Router;
+use futures::TryStreamExt;
+use rig::completion::{CompletionModel, ModelChoice};
+use rig::embeddings::EmbeddingModel;
+use serde_json::{json, Value};
+
+use rag_system::config::Config;
+use rag_system::providers::{self, ollama, openai, Endpoint, HttpClient};
+use rag_system::streaming::StreamingCompletionModel;
+
+/// A request as the stand-in server received it
+#[derive(Clone, Debug)]
+struct Received {
+    path: String,
+    query: Option<String>,
+    headers: HeaderMap,
+    body: Value,
+}
+
+type Respond = dyn Fn(&Received) -> (StatusCode, String) + Send + Sync;
+
+#[derive(Clone)]
+struct StandIn {
+    received: Arc<Mutex<Vec<Received>>>,
+    respond: Arc<Respond>,
+}
+
+impl StandIn {
+    // Serves on a free local port, answering every request with `respond`
+    async fn start(
+        respond: impl Fn(&Received) -> (StatusCode, String) + Send + Sync + 'static,
+    ) -> (Self, String) {
+        let stand_in = Self {
+            received: Arc::default(),
+            respond: Arc::new(respond),
+        };
+        let router = Router::new().fallback(record).with_state(stand_in.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (stand_in, url)
+    }
+
+    fn last(&self) -> Received {
+        self.received.lock().unwrap().last().cloned().unwrap()
+    }
+}
+
+async fn record(
+    State(stand_in): State<StandIn>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: String,
+) -> (StatusCode, String) {
+    let received = Received {
+        path: uri.path().to_string(),
+        query: uri.query().map(String::from),
+        headers,
+        body: serde_json::from_str(&body).unwrap_or(Value::Null),
+    };
+    let response = (stand_in.respond)(&received);
+    stand_in.received.lock().unwrap().push(received);
+    response
+}
+
+fn ok(body: Value) -> (StatusCode, String) {
+    (StatusCode::OK, body.to_string())
+}
+
+fn http(base_url: &str) -> HttpClient {
+    HttpClient::new(&Endpoint {
+        base_url: base_url.to_string(),
+        ..Endpoint::default()
+    })
+    .unwrap()
+}
+
+#[tokio::test]
+async fn ollama_embeds_every_text() {
+    let (stand_in, url) =
+        StandIn::start(|_| ok(json!({ "embeddings": [[1.0, 0.0], [0.0, 1.0]] }))).await;
+    let model = ollama::Client::new(http(&url)).embedding_model("nomic-embed-text");
+
+    let embeddings = model
+        .embed_texts(["first".to_string(), "second".to_string()])
+        .await
+        .unwrap();
+
+    assert_eq!(embeddings[0].document, "first");
+    assert_eq!(embeddings[1].vec, [0.0, 1.0]);
+    let received = stand_in.last();
+    assert_eq!(received.path, "/api/embed");
+    assert_eq!(
+        received.body,
+        json!({ "model": "nomic-embed-text", "input": ["first", "second"] })
+    );
+}
+
+#[tokio::test]
+async fn ollama_missing_embeddings_are_an_error() {
+    let (_stand_in, url) = StandIn::start(|_| ok(json!({ "embeddings": [[1.0, 0.0]] }))).await;
+    let model = ollama::Client::new(http(&url)).embedding_model("nomic-embed-text");
+
+    let error = model
+        .embed_texts(["first".to_string(), "second".to_string()])
+        .await
+        .unwrap_err();
+
+    assert!(
+        error.to_string().contains("Expected 2 embeddings, got 1"),
+        "{error}"
+    );
+}
+
+#[tokio::test]
+async fn ollama_chat_sends_the_conversation() {
+    let (stand_in, url) = StandIn::start(|_| {
+        ok(json!({ "message": { "role": "assistant", "content": "Four hours." }, "done": true }))
+    })
+    .await;
+    let model = ollama::Client::new(http(&url)).completion_model("llama3.1");
+
+    let response = model
+        .completion_request("How long does dough rise?")
+        .preamble("Be brief.".to_string())
+        .temperature(0.2)
+        .send()
+        .await
+        .unwrap();
+
+    assert!(matches!(response.choice, ModelChoice::Message(text) if text == "Four hours."));
+    let received = stand_in.last();
+    assert_eq!(received.path, "/api/chat");
+    assert_eq!(
+        received.body,
+        json!({
+            "model": "llama3.1",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "How long does dough rise?" },
+            ],
+            "options": { "temperature": 0.2 },
+            "stream": false,
+        })
+    );
+}
+
+#[tokio::test]
+async fn ollama_errors_carry_the_server_message() {
+    let (_stand_in, url) = StandIn::start(|_| {
+        (
+            StatusCode::NOT_FOUND,
+            json!({ "error": "model \"llama9\" not found" }).to_string(),
+        )
+    })
+    .await;
+    let model = ollama::Client::new(http(&url)).completion_model("llama9");
+
+    let error = model.completion_request("Hi").send().await.unwrap_err();
+
+    let message = error.to_string();
+    assert!(
+        message.contains("returned 404 Not Found: model \"llama9\" not found"),
+        "{message}"
+    );
+}
+
+#[tokio::test]
+async fn ollama_streams_each_line() {
+    let (stand_in, url) = StandIn::start(|_| {
+        let lines = [
+            json!({ "message": { "role": "assistant", "content": "Four " }, "done": false }),
+            json!({ "message": { "role": "assistant", "content": "hours." }, "done": false }),
+            json!({ "message": { "role": "assistant", "content": "" }, "done": true }),
+        ];
+        let body: String = lines.iter().map(|line| format!("{line}\n")).collect();
+        (StatusCode::OK, body)
+    })
+    .await;
+    let model = ollama::Client::new(http(&url)).completion_model("llama3.1");
+
+    let request = model.completion_request("How long?").build();
+    let pieces: Vec<String> = model
+        .stream(request)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+
+    assert_eq!(pieces, ["Four ", "hours."]);
+    assert_eq!(stand_in.last().body["stream"], true);
+}
+
+#[tokio::test]
+async fn gateway_settings_reach_the_server() {
+    // Embeddings may come back in any order; `index` says which input each belongs to
+    let (stand_in, url) = StandIn::start(|_| {
+        ok(json!({ "data": [
+            { "index": 1, "embedding": [0.0, 1.0] },
+            { "index": 0, "embedding": [1.0, 0.0] },
+        ] }))
+    })
+    .await;
+    let mut config = Config::default();
+    config
+        .set("embedding.base_url", &format!("{url}/gateway/v1/"))
+        .unwrap();
+    config.set("embedding.api_version", "2024-06-01").unwrap();
+    config.set("embedding.headers.X-Team", "docs").unwrap();
+    config
+        .set("embedding.api_key_env", "RAG_TEST_GATEWAY_KEY")
+        .unwrap();
+    // The key is looked up through a stand-in environment, not the test process's
+    let endpoint = config
+        .embedding
+        .endpoint_with_env(&|name| (name == "RAG_TEST_GATEWAY_KEY").then(|| "sk-test".into()))
+        .unwrap();
+    let model = openai::Client::new(HttpClient::new(&endpoint).unwrap())
+        .embedding_model(config.embedding.model());
+
+    let embeddings = model
+        .embed_texts(["first".to_string(), "second".to_string()])
+        .await
+        .unwrap();
+
+    assert_eq!(embeddings[0].document, "first");
+    assert_eq!(embeddings[0].vec, [1.0, 0.0]);
+    assert_eq!(embeddings[1].vec, [0.0, 1.0]);
+    let received = stand_in.last();
+    assert_eq!(received.path, "/gateway/v1/embeddings");
+    assert_eq!(received.query.as_deref(), Some("api-version=2024-06-01"));
+    assert_eq!(received.headers["authorization"], "Bearer sk-test");
+    assert_eq!(received.headers["x-team"], "docs");
+    assert_eq!(
+        received.body,
+        json!({ "model": "text-embedding-ada-002", "input": ["first", "second"] })
+    );
+}
+
+#[tokio::test]
+async fn openai_chat_sends_the_conversation() {
+    let (stand_in, url) = StandIn::start(|_| {
+        ok(json!({ "choices": [{ "message": { "role": "assistant", "content": "Four hours." } }] }))
+    })
+    .await;
+    let mut config = Config::default();
+    config.set("completion.base_url", &url).unwrap();
+    config.set("completion.model", "gpt-4o-mini").unwrap();
+    let model = providers::CompletionModel::from_config(&config.completion).unwrap();
+
+    let response = model
+        .completion_request("How long does dough rise?")
+        .preamble("Be brief.".to_string())
+        .temperature(0.2)
+        .send()
+        .await
+        .unwrap();
+
+    assert!(matches!(response.choice, ModelChoice::Message(text) if text == "Four hours."));
+    let received = stand_in.last();
+    assert_eq!(received.path, "/chat/completions");
+    assert_eq!(received.query, None);
+    assert_eq!(
+        received.body,
+        json!({
+            "model": "gpt-4o-mini",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "How long does dough rise?" },
+            ],
+            "temperature": 0.2,
+        })
+    );
+}
+
+#[tokio::test]
+async fn openai_errors_carry_the_server_message() {
+    let (_stand_in, url) = StandIn::start(|_| {
+        let error =
+            json!({ "error": { "message": "Incorrect API key", "type": "invalid_request_error" } });
+        (StatusCode::UNAUTHORIZED, error.to_string())
+    })
+    .await;
+    let model = providers::openai::Client::new(http(&url)).completion_model("gpt-4");
+
+    let error = model.completion_request("Hi").send().await.unwrap_err();
+
+    let message = error.to_string();
+    assert!(
+        message.contains("returned 401 Unauthorized: Incorrect API key"),
+        "{message}"
+    );
+}
+
+#[tokio::test]
+async fn openai_streams_each_event() {
+    let (stand_in, url) = StandIn::start(|_| {
+        let events = [
+            json!({ "choices": [{ "delta": { "role": "assistant" } }] }).to_string(),
+            json!({ "choices": [{ "delta": { "content": "Four " } }] }).to_string(),
+            json!({ "choices": [{ "delta": { "content": "hours." } }] }).to_string(),
+            "[DONE]".to_string(),
+        ];
+        let body: String = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .collect();
+        (StatusCode::OK, body)
+    })
+    .await;
+    let model = providers::openai::Client::new(http(&url)).completion_model("gpt-4");
+
+    let request = model.completion_request("How long?").build();
+    let pieces: Vec<String> = model
+        .stream(request)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+
+    assert_eq!(pieces, ["Four ", "hours."]);
+    assert_eq!(stand_in.last().body["stream"], true);
+}
@@ -0,0 +1,323 @@
+// Tests of the HTTP API, sending requests straight to the router with hashed embeddings and
+// the mock chat model
+use std::fs;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+use rag_system::config::Config;
+use rag_system::providers::hashed;
+use rag_system::providers::mock;
+use rag_system::server;
+use rag_system::tokens::count_tokens;
+use tempfile::TempDir;
+
+mod common;
+
+use common::{BAKING, GARDENING};
+
+// The shared documents, with one chunk given to the agent per question
+fn setup() -> (TempDir, Config) {
+    let (dir, mut config) = common::setup();
+    config.set("retrieval.top_k", "1").unwrap();
+    config.validate().unwrap();
+    (dir, config)
+}
+
+async fn router(config: &Config, model: &mock::CompletionModel) -> Router {
+    server::router(
+        config.clone(),
+        hashed::EmbeddingModel::default(),
+        model.clone(),
+        false,
+    )
+    .await
+    .unwrap()
+}
+
+// Sends one request, returning the status and the JSON body
+async fn send(
+    router: &Router,
+    method: Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let request = Request::builder().method(method).uri(uri);
+    let request = match body {
+        Some(body) => request
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap();
+
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+// Sends a request answered with server-sent events, returning each event's name and data
+async fn send_streaming(router: &Router, uri: &str, body: Value) -> Vec<(Option<String>, String)> {
+    let request = Request::post(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    String::from_utf8(bytes.to_vec())
+        .unwrap()
+        .split("\n\n")
+        .filter_map(|event| {
+            let field = |name: &str| event.lines().find_map(|line| line.strip_prefix(name));
+            Some((
+                field("event: ").map(String::from),
+                field("data: ")?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+fn json(data: &str) -> Value {
+    serde_json::from_str(data).unwrap()
+}
+
+#[tokio::test]
+async fn health_reports_ok() {
+    let (_dir, config) = setup();
+    let router = router(&config, &mock::CompletionModel::new(|_| String::new())).await;
+
+    let (status, body) = send(&router, Method::GET, "/health", None).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "status": "ok" }));
+}
+
+#[tokio::test]
+async fn query_returns_the_answer_with_citations() {
+    let (_dir, config) = setup();
+    let model = mock::CompletionModel::new(|_| "Four to six hours [baking.md#0].".into());
+    let router = router(&config, &model).await;
+
+    let question = json!({ "question": "How long should sourdough dough proof?" });
+    let (status, body) = send(&router, Method::POST, "/query", Some(question)).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["answer"], "Four to six hours [1].");
+    assert_eq!(body["citations"][0]["number"], 1);
+    assert_eq!(body["citations"][0]["source"], "baking.md");
+    assert_eq!(body["uncited"], json!([]));
+}
+
+#[tokio::test]
+async fn query_can_stream_server_sent_events() {
+    let (_dir, config) = setup();
+    let model = mock::CompletionModel::new(|_| "Four to six hours [baking.md#0].".into());
+    let router = router(&config, &model).await;
+
+    let question = json!({ "question": "How long should sourdough dough proof?", "stream": true });
+    let events = send_streaming(&router, "/query", question).await;
+    let (done, tokens) = events.split_last().unwrap();
+
+    let text: String = tokens
+        .iter()
+        .map(|(name, data)| {
+            assert_eq!(name.as_deref(), Some("token"));
+            json(data)["text"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(text, "Four to six hours [1].");
+    assert_eq!(done.0.as_deref(), Some("done"));
+    assert_eq!(json(&done.1)["answer"], text);
+    assert_eq!(json(&done.1)["citations"][0]["id"], "baking.md#0");
+}
+
+#[tokio::test]
+async fn openai_chat_completions_answer_the_last_message() {
+    let (_dir, config) = setup();
+    let model = mock::CompletionModel::new(|_| "Overnight in the fridge [baking.md#0].".into());
+    let router = router(&config, &model).await;
+
+    let request = json!({
+        "model": "docs",
+        "messages": [
+            { "role": "user", "content": "How long should sourdough dough proof?" },
+            { "role": "assistant", "content": "Four to six hours [1]." },
+            { "role": "user", "content": [{ "type": "text", "text": "Can sourdough proof in the fridge?" }] },
+        ],
+    });
+    let (status, body) = send(&router, Method::POST, "/v1/chat/completions", Some(request)).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["object"], "chat.completion");
+    assert_eq!(body["model"], "docs");
+    let content = body["choices"][0]["message"]["content"].as_str().unwrap();
+    assert!(content.starts_with("Overnight in the fridge [1].\n\nSources:\n  [1] baking.md"));
+    assert_eq!(body["choices"][0]["finish_reason"], "stop");
+
+    let sent = model.last_request().unwrap();
+    assert_eq!(sent.prompt, "Can sourdough proof in the fridge?");
+    assert_eq!(sent.chat_history.len(), 2);
+
+    // Estimated from the model's response, without the footer, and from everything it was
+    // sent, which is more than the messages alone
+    let usage = &body["usage"];
+    let completion_tokens = count_tokens("Overnight in the fridge [baking.md#0].");
+    assert_eq!(usage["completion_tokens"], completion_tokens);
+    let prompt_tokens = usage["prompt_tokens"].as_u64().unwrap() as usize;
+    let message_tokens: usize = [
+        "How long should sourdough dough proof?",
+        "Four to six hours [1].",
+        sent.prompt.as_str(),
+    ]
+    .iter()
+    .map(|text| count_tokens(text))
+    .sum();
+    assert!(
+        prompt_tokens > message_tokens + count_tokens(BAKING),
+        "{usage}"
+    );
+    assert_eq!(usage["total_tokens"], prompt_tokens + completion_tokens);
+
+    let request = json!({ "messages": [{ "role": "assistant", "content": "Hello" }] });
+    let (status, body) = send(&router, Method::POST, "/v1/chat/completions", Some(request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+}
+
+#[tokio::test]
+async fn openai_chat_completions_can_stream() {
+    let (_dir, config) = setup();
+    let model = mock::CompletionModel::new(|_| "Four to six hours [baking.md#0].".into());
+    let router = router(&config, &model).await;
+
+    let request = json!({
+        "messages": [{ "role": "user", "content": "How long should sourdough dough proof?" }],
+        "stream": true,
+    });
+    let events = send_streaming(&router, "/v1/chat/completions", request).await;
+    let (done, chunks) = events.split_last().unwrap();
+    assert_eq!(done.1, "[DONE]");
+
+    let chunks: Vec<Value> = chunks.iter().map(|(_, data)| json(data)).collect();
+    let content: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert!(content.starts_with("Four to six hours [1].\n\nSources:\n  [1] baking.md"));
+    assert!(chunks
+        .iter()
+        .all(|chunk| chunk["object"] == "chat.completion.chunk"));
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+}
+
+#[tokio::test]
+async fn empty_question_is_rejected() {
+    let (_dir, config) = setup();
+    let model = mock::CompletionModel::new(|_| String::new());
+    let router = router(&config, &model).await;
+
+    let question = json!({ "question": "  " });
+    let (status, body) = send(&router, Method::POST, "/query", Some(question)).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].is_string());
+    assert!(model.requests().is_empty());
+}
+
+#[tokio::test]
+async fn chat_returns_the_history_for_the_next_turn() {
+    let (_dir, mut config) = setup();
+    // Only the answers use up canned responses
+    config.set("memory.condense", "false").unwrap();
+    let model = mock::CompletionModel::with_responses([
+        "Four to six hours [baking.md#0].".to_string(),
+        "Overnight in the fridge [baking.md#0].".to_string(),
+    ]);
+    let router = router(&config, &model).await;
+
+    let first = json!({ "message": "How long should sourdough dough proof?" });
+    let (_, body) = send(&router, Method::POST, "/chat", Some(first)).await;
+    let history = body["history"].clone();
+    assert_eq!(history.as_array().unwrap().len(), 2);
+    assert_eq!(history[1]["content"], "Four to six hours [baking.md#0].");
+
+    let second = json!({ "message": "Can sourdough proof in the fridge?", "history": history });
+    let (status, body) = send(&router, Method::POST, "/chat", Some(second)).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["answer"], "Overnight in the fridge [1].");
+    assert_eq!(body["history"].as_array().unwrap().len(), 4);
+    assert_eq!(model.last_request().unwrap().chat_history.len(), 2);
+}
+
+#[tokio::test]
+async fn ingest_picks_up_new_documents() {
+    let (_dir, config) = setup();
+    let model = mock::CompletionModel::new(|_| "Full sun.".into());
+    let router = router(&config, &model).await;
+
+    let (_, body) = send(&router, Method::GET, "/documents", None).await;
+    assert_eq!(body["documents"].as_array().unwrap().len(), 2);
+
+    fs::write(config.documents.dir.join("gardening.md"), GARDENING).unwrap();
+    let (status, body) = send(&router, Method::POST, "/ingest", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "files": 3, "chunks": 3 }));
+
+    let (_, body) = send(&router, Method::GET, "/documents", None).await;
+    assert!(body["documents"]
+        .as_array()
+        .unwrap()
+        .contains(&json!({ "id": "gardening.md", "chunks": 1 })));
+
+    let question = json!({ "question": "How much sun do tomato plants want?" });
+    send(&router, Method::POST, "/query", Some(question)).await;
+    assert_eq!(
+        model.last_request().unwrap().document_ids(),
+        ["gardening.md#0"]
+    );
+}
+
+#[tokio::test]
+async fn ingest_rejects_invalid_bodies() {
+    let (_dir, config) = setup();
+    let router = router(&config, &mock::CompletionModel::new(|_| String::new())).await;
+
+    let (status, body) = send(
+        &router,
+        Method::POST,
+        "/ingest",
+        Some(json!({ "reindex": "yes" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Invalid request body"));
+
+    let request = Request::post("/ingest")
+        .header("content-type", "application/json")
+        .body(Body::from("{reindex"))
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let (status, _) = send(
+        &router,
+        Method::POST,
+        "/ingest",
+        Some(json!({ "reindex": true })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}